@@ -0,0 +1,116 @@
+use anyhow::{Context, Error};
+use std::path::{Path, PathBuf};
+
+/// Settings from the `[tool.extism-py]` table of the closest pyproject.toml. The
+/// command line levels take precedence, the lists are merged with the command line
+/// ones and the switches are turned on by either
+#[derive(Debug, Default)]
+pub(crate) struct Config {
+    pub include: Vec<String>,
+    pub exclude: Vec<String>,
+    pub default_excludes: Option<bool>,
+    pub wasi_stdio: Option<bool>,
+    pub stdout_level: Option<String>,
+    pub stderr_level: Option<String>,
+    pub preload: Vec<String>,
+}
+
+const KEYS: &[&str] = &[
+    "include",
+    "exclude",
+    "default-excludes",
+    "wasi-stdio",
+    "stdout-level",
+    "stderr-level",
+    "preload",
+];
+
+fn find_pyproject(input: &Path) -> Option<PathBuf> {
+    let input = input.canonicalize().ok()?;
+    input
+        .ancestors()
+        .skip(1)
+        .map(|dir| dir.join("pyproject.toml"))
+        .find(|path| path.exists())
+}
+
+fn boolean(table: &toml::Table, key: &str) -> Result<Option<bool>, Error> {
+    match table.get(key) {
+        Some(value) => match value.as_bool() {
+            Some(value) => Ok(Some(value)),
+            None => anyhow::bail!("`{key}` must be a boolean"),
+        },
+        None => Ok(None),
+    }
+}
+
+fn log_level(table: &toml::Table, key: &str) -> Result<Option<String>, Error> {
+    match table.get(key) {
+        Some(value) => match value.as_str() {
+            Some(value) if crate::options::LOG_LEVELS.contains(&value) => {
+                Ok(Some(value.to_string()))
+            }
+            _ => anyhow::bail!(
+                "`{key}` must be one of {}",
+                crate::options::LOG_LEVELS.join(", ")
+            ),
+        },
+        None => Ok(None),
+    }
+}
+
+fn strings(table: &toml::Table, key: &str) -> Result<Vec<String>, Error> {
+    let Some(value) = table.get(key) else {
+        return Ok(vec![]);
+    };
+    let Some(items) = value.as_array() else {
+        anyhow::bail!("`{key}` must be a list of strings");
+    };
+    items
+        .iter()
+        .map(|item| match item.as_str() {
+            Some(item) => Ok(item.to_string()),
+            None => anyhow::bail!("`{key}` must be a list of strings"),
+        })
+        .collect()
+}
+
+/// Load the config for the plugin at `input`
+pub(crate) fn load(input: &Path) -> Result<Config, Error> {
+    let Some(path) = find_pyproject(input) else {
+        return Ok(Config::default());
+    };
+    let data = std::fs::read_to_string(&path)?;
+    let pyproject: toml::Table = data
+        .parse()
+        .with_context(|| format!("Unable to parse {}", path.display()))?;
+    let Some(table) = pyproject
+        .get("tool")
+        .and_then(|x| x.get("extism-py"))
+        .and_then(|x| x.as_table())
+    else {
+        return Ok(Config::default());
+    };
+
+    for key in table.keys() {
+        if !KEYS.contains(&key.as_str()) {
+            log::warn!(
+                "Unknown key `{key}` in [tool.extism-py] of {}",
+                path.display()
+            );
+        }
+    }
+
+    let config = || -> Result<Config, Error> {
+        Ok(Config {
+            include: strings(table, "include")?,
+            exclude: strings(table, "exclude")?,
+            default_excludes: boolean(table, "default-excludes")?,
+            wasi_stdio: boolean(table, "wasi-stdio")?,
+            stdout_level: log_level(table, "stdout-level")?,
+            stderr_level: log_level(table, "stderr-level")?,
+            preload: strings(table, "preload")?,
+        })
+    };
+    config().with_context(|| format!("Invalid [tool.extism-py] in {}", path.display()))
+}
@@ -0,0 +1,337 @@
+use anyhow::{Context, Error};
+use std::collections::HashMap;
+use std::io::{BufRead, Write};
+use std::path::Path;
+use wagen::parser::{KnownCustom, Name, Parser, Payload};
+
+const SOURCES_SECTION: &str = "extism-py.sources";
+
+/// A block of lines in one of the Python modules embedded in the plugin
+#[derive(Debug, Clone)]
+pub(crate) struct Source {
+    /// Filename the code was compiled with, as it appears in Python tracebacks
+    pub name: String,
+    /// File the lines were read from
+    pub file: String,
+    /// First line of the block in `name`
+    pub start: usize,
+    pub lines: Vec<String>,
+}
+
+impl Source {
+    pub fn new(name: &str, file: &str, start: usize, code: &str) -> Self {
+        Source {
+            name: name.to_string(),
+            file: file.to_string(),
+            start,
+            lines: code.lines().map(String::from).collect(),
+        }
+    }
+}
+
+/// The modules the core compiles itself, by the filename it compiles them with
+const CORE_SOURCES: &[(&str, &str, &str)] = &[
+    (
+        "<prelude>",
+        "prelude.py",
+        include_str!("../../lib/src/prelude.py"),
+    ),
+    (
+        "<http_compat>",
+        "http_compat.py",
+        include_str!("../../lib/src/http_compat.py"),
+    ),
+    (
+        "<tree_shake>",
+        "tree_shake.py",
+        include_str!("../../lib/src/tree_shake.py"),
+    ),
+    (
+        "<precompile>",
+        "precompile.py",
+        include_str!("../../lib/src/precompile.py"),
+    ),
+    (
+        "<reproducible>",
+        "reproducible.py",
+        include_str!("../../lib/src/reproducible.py"),
+    ),
+    (
+        "<size_report>",
+        "size_report.py",
+        include_str!("../../lib/src/size_report.py"),
+    ),
+];
+
+/// Sources of the modules embedded in every plugin by the core
+pub(crate) fn core_sources() -> Vec<Source> {
+    CORE_SOURCES
+        .iter()
+        .map(|(name, file, code)| Source::new(name, file, 1, code))
+        .collect()
+}
+
+fn encode_sources(sources: &[Source]) -> Vec<u8> {
+    let mut out = String::new();
+    for source in sources {
+        out += &format!(
+            "{}\t{}\t{}\t{}\n",
+            source.name,
+            source.file,
+            source.start,
+            source.lines.len()
+        );
+        for line in &source.lines {
+            out += line;
+            out.push('\n');
+        }
+    }
+    out.into_bytes()
+}
+
+fn decode_sources(data: &[u8]) -> Result<Vec<Source>, Error> {
+    let data = std::str::from_utf8(data)?;
+    let mut lines = data.lines();
+    let mut sources = vec![];
+    while let Some(header) = lines.next() {
+        let fields: Vec<&str> = header.split('\t').collect();
+        let [name, file, start, count] = fields[..] else {
+            anyhow::bail!("Invalid {SOURCES_SECTION} section");
+        };
+        let count: usize = count.parse()?;
+        sources.push(Source {
+            name: name.to_string(),
+            file: file.to_string(),
+            start: start.parse()?,
+            lines: lines.by_ref().take(count).map(String::from).collect(),
+        });
+    }
+    Ok(sources)
+}
+
+fn write_leb128(out: &mut Vec<u8>, mut value: usize) {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+/// Copy `wasm`, which must still have its name section, to `dest` and record the
+/// embedded Python sources in a custom section
+pub(crate) fn write_sidecar(
+    wasm: impl AsRef<Path>,
+    dest: impl AsRef<Path>,
+    sources: &[Source],
+) -> Result<(), Error> {
+    let mut module = std::fs::read(wasm)?;
+    let data = encode_sources(sources);
+
+    let mut section = vec![];
+    write_leb128(&mut section, SOURCES_SECTION.len());
+    section.extend_from_slice(SOURCES_SECTION.as_bytes());
+    section.extend_from_slice(&data);
+
+    module.push(0);
+    write_leb128(&mut module, section.len());
+    module.extend_from_slice(&section);
+    std::fs::write(dest, module)?;
+    Ok(())
+}
+
+struct DebugInfo {
+    functions: HashMap<u32, String>,
+    sources: Vec<Source>,
+}
+
+impl DebugInfo {
+    fn load(path: &Path) -> Result<Self, Error> {
+        let wasm = std::fs::read(path)
+            .with_context(|| format!("Unable to read debug info from {}", path.display()))?;
+        let mut functions = HashMap::new();
+        let mut sources = vec![];
+        for payload in Parser::new(0).parse_all(&wasm) {
+            let Payload::CustomSection(section) = payload? else {
+                continue;
+            };
+            if section.name() == SOURCES_SECTION {
+                sources = decode_sources(section.data())?;
+            } else if let KnownCustom::Name(names) = section.as_known() {
+                for name in names {
+                    if let Name::Function(map) = name? {
+                        for naming in map {
+                            let naming = naming?;
+                            functions.insert(naming.index, naming.name.to_string());
+                        }
+                    }
+                }
+            }
+        }
+        Ok(DebugInfo { functions, sources })
+    }
+
+    fn function(&self, index: &str) -> Option<String> {
+        let index: u32 = index.parse().ok()?;
+        self.functions.get(&index).cloned()
+    }
+
+    fn python_frame(&self, line: &str) -> Option<String> {
+        let (indent, rest) = line.split_at(line.find("File \"")?);
+        let rest = &rest["File \"".len()..];
+        let (name, rest) = rest.split_once("\", line ")?;
+        let end = rest
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(rest.len());
+        let lineno: usize = rest[..end].parse().ok()?;
+
+        let source = self
+            .sources
+            .iter()
+            .find(|s| s.name == name && lineno >= s.start && lineno < s.start + s.lines.len())?;
+        let offset = lineno - source.start;
+        Some(format!(
+            "{indent}File \"{}\", line {}{}\n{indent}  {}",
+            source.file,
+            offset + 1,
+            &rest[end..],
+            source.lines[offset].trim()
+        ))
+    }
+
+    fn symbolize(&self, line: &str) -> String {
+        if let Some(frame) = self.python_frame(line) {
+            return frame;
+        }
+
+        // wasmtime, V8 and wazero (used by the Extism CLI) formats respectively
+        let line = replace_between(line, "<wasm function ", ">", |i| self.function(i));
+        let line = replace_between(&line, "wasm-function[", "]", |i| self.function(i));
+        replace_between(&line, ".$", "(", |i| {
+            self.function(i).map(|name| format!(".{name}("))
+        })
+    }
+}
+
+/// Replace every `{prefix}{index}{suffix}` in `line` with the result of `f(index)`,
+/// keeping the original text when `f` returns `None`
+fn replace_between(
+    line: &str,
+    prefix: &str,
+    suffix: &str,
+    f: impl Fn(&str) -> Option<String>,
+) -> String {
+    let mut out = String::new();
+    let mut rest = line;
+    while let Some(start) = rest.find(prefix) {
+        let after = &rest[start + prefix.len()..];
+        let Some(end) = after.find(suffix) else {
+            break;
+        };
+        out += &rest[..start];
+        match f(&after[..end]) {
+            Some(replacement) => out += &replacement,
+            None => out += &rest[start..start + prefix.len() + end + suffix.len()],
+        }
+        rest = &after[end + suffix.len()..];
+    }
+    out += rest;
+    out
+}
+
+/// Read a trap backtrace from stdin and write it to stdout with wasm function
+/// indices and embedded Python source locations resolved
+pub(crate) fn symbolize(debug_info: &Path) -> Result<(), Error> {
+    let info = DebugInfo::load(debug_info)?;
+    let stdout = std::io::stdout();
+    let mut stdout = stdout.lock();
+    for line in std::io::stdin().lock().lines() {
+        writeln!(stdout, "{}", info.symbolize(&line?))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn info(sources: Vec<Source>) -> DebugInfo {
+        DebugInfo {
+            functions: HashMap::from([(7, "extism_call".to_string())]),
+            sources,
+        }
+    }
+
+    #[test]
+    fn sources_roundtrip() {
+        let sources = vec![
+            Source::new(
+                "<source>",
+                "plugin.py",
+                1,
+                "import extism\n\ndef f():\n    pass\n",
+            ),
+            Source::new("<source>", "invoke.py", 6, "def __invoke(index):\n    pass"),
+            Source::new("<prelude>", "prelude.py", 1, ""),
+        ];
+        let decoded = decode_sources(&encode_sources(&sources)).unwrap();
+        assert_eq!(decoded.len(), sources.len());
+        for (a, b) in decoded.iter().zip(&sources) {
+            assert_eq!(a.name, b.name);
+            assert_eq!(a.file, b.file);
+            assert_eq!(a.start, b.start);
+            assert_eq!(a.lines, b.lines);
+        }
+    }
+
+    #[test]
+    fn decode_invalid_header() {
+        assert!(decode_sources(b"<source>\tplugin.py\n").is_err());
+        assert!(decode_sources(b"<source>\tplugin.py\t1\tmany\n").is_err());
+    }
+
+    #[test]
+    fn replace_between_indices() {
+        let f = |i: &str| (i == "1").then(|| "one".to_string());
+        assert_eq!(
+            replace_between("a[1] b[2] c[1", "[", "]", f),
+            "aone b[2] c[1"
+        );
+        assert_eq!(replace_between("no match", "[", "]", f), "no match");
+    }
+
+    #[test]
+    fn python_frame_resolves_file_and_line() {
+        let info = info(vec![
+            Source::new("<source>", "plugin.py", 1, "a = 1\nb = 2\n"),
+            Source::new("<source>", "invoke.py", 4, "def __invoke():\n    raise"),
+        ]);
+        assert_eq!(
+            info.python_frame("  File \"<source>\", line 5, in __invoke")
+                .unwrap(),
+            "  File \"invoke.py\", line 2, in __invoke\n    raise"
+        );
+        assert_eq!(
+            info.python_frame("File \"<source>\", line 2").unwrap(),
+            "File \"plugin.py\", line 2\n  b = 2"
+        );
+        assert!(info.python_frame("File \"<source>\", line 3").is_none());
+        assert!(info.python_frame("File \"<prelude>\", line 1").is_none());
+        assert!(info.python_frame("no frame").is_none());
+    }
+
+    #[test]
+    fn symbolize_wasm_functions() {
+        let info = info(vec![]);
+        assert_eq!(info.symbolize("0: <wasm function 7>"), "0: extism_call");
+        assert_eq!(
+            info.symbolize("at wasm-function[7]:0x10"),
+            "at extism_call:0x10"
+        );
+        assert_eq!(info.symbolize("core.$7(...)"), "core.extism_call(...)");
+        assert_eq!(info.symbolize("<wasm function 8>"), "<wasm function 8>");
+    }
+}
@@ -0,0 +1,244 @@
+use anyhow::{Context, Error};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use tempfile::TempDir;
+
+/// Excluded unless `--no-default-excludes` is passed
+const DEFAULT_EXCLUDES: &[&str] = &[".*", "__pycache__", "test", "tests"];
+
+/// Include/exclude rules applied to the `PYTHONPATH` directories before they are made
+/// available to the plugin during initialization
+///
+/// Patterns without a `/` are matched against the name of every file and directory,
+/// other patterns against the path relative to the `PYTHONPATH` entry. `*` and `?`
+/// don't match `/`, `**` matches any number of directories. Excluding a directory
+/// excludes everything in it, include patterns only apply to files.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub(crate) struct SourceFilter {
+    include: Vec<String>,
+    exclude: Vec<String>,
+}
+
+impl SourceFilter {
+    pub fn new(include: Vec<String>, mut exclude: Vec<String>, default_excludes: bool) -> Self {
+        if default_excludes {
+            exclude.extend(DEFAULT_EXCLUDES.iter().map(|x| x.to_string()));
+        }
+        SourceFilter { include, exclude }
+    }
+
+    fn is_excluded(&self, path: &str) -> bool {
+        self.exclude.iter().any(|pattern| matches(pattern, path))
+    }
+
+    fn is_included(&self, path: &str) -> bool {
+        self.include.is_empty() || self.include.iter().any(|pattern| matches(pattern, path))
+    }
+
+    /// Whether there are no rules, the directories can then be used as they are
+    pub fn is_empty(&self) -> bool {
+        self.include.is_empty() && self.exclude.is_empty()
+    }
+
+    /// Copy the files in `src` that pass the filter to `dest`
+    pub fn stage(&self, src: &Path, dest: &Path) -> Result<(), Error> {
+        let mut parents = vec![];
+        self.stage_dir(src, dest, "", &mut parents)
+            .with_context(|| format!("Unable to stage {}", src.display()))
+    }
+
+    // `parents` holds the directories being staged, a symlink to one of them would
+    // be a cycle and is skipped
+    fn stage_dir(
+        &self,
+        src: &Path,
+        dest: &Path,
+        prefix: &str,
+        parents: &mut Vec<PathBuf>,
+    ) -> Result<(), Error> {
+        let canonical = src.canonicalize()?;
+        if parents.contains(&canonical) {
+            log::warn!("Skipping {}, it links to one of its parents", src.display());
+            return Ok(());
+        }
+        parents.push(canonical);
+
+        std::fs::create_dir_all(dest)?;
+        for entry in std::fs::read_dir(src)? {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().to_string();
+            let path = format!("{prefix}{name}");
+            if self.is_excluded(&path) {
+                continue;
+            }
+
+            let src = entry.path();
+            let mut file_type = entry.file_type()?;
+            if file_type.is_symlink() {
+                match std::fs::metadata(&src) {
+                    Ok(metadata) => file_type = metadata.file_type(),
+                    // Dangling links are skipped
+                    Err(_) => continue,
+                }
+            }
+            if file_type.is_dir() {
+                self.stage_dir(&src, &dest.join(&name), &format!("{path}/"), parents)?;
+            } else if self.is_included(&path) {
+                std::fs::copy(&src, dest.join(&name))?;
+            }
+        }
+
+        parents.pop();
+        Ok(())
+    }
+}
+
+/// Filtered copies of the `PYTHONPATH` directories, shared by the plugins built in one
+/// invocation so each directory is only copied once per filter
+pub(crate) struct Staging {
+    dir: TempDir,
+    staged: Mutex<HashMap<(PathBuf, SourceFilter), PathBuf>>,
+}
+
+impl Staging {
+    pub fn new() -> Result<Self, Error> {
+        Ok(Staging {
+            dir: TempDir::new()?,
+            staged: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// The copy of `src` filtered by `filter`, or `src` itself when there is nothing
+    /// to filter
+    pub fn get(&self, src: &Path, filter: &SourceFilter) -> Result<PathBuf, Error> {
+        if filter.is_empty() {
+            return Ok(src.to_path_buf());
+        }
+
+        // Held while copying, so a directory is never staged twice at the same time
+        let mut staged = self.staged.lock().expect("Staging lock poisoned");
+        let key = (src.to_path_buf(), filter.clone());
+        if let Some(dest) = staged.get(&key) {
+            return Ok(dest.clone());
+        }
+        let dest = self.dir.path().join(staged.len().to_string());
+        filter.stage(src, &dest)?;
+        staged.insert(key, dest.clone());
+        Ok(dest)
+    }
+}
+
+fn matches(pattern: &str, path: &str) -> bool {
+    if !pattern.contains('/') {
+        let name = path.rsplit('/').next().unwrap_or_default();
+        return glob(pattern.as_bytes(), name.as_bytes());
+    }
+    let pattern: Vec<&str> = pattern.trim_start_matches('/').split('/').collect();
+    let path: Vec<&str> = path.split('/').collect();
+    glob_segments(&pattern, &path)
+}
+
+fn glob_segments(pattern: &[&str], path: &[&str]) -> bool {
+    match (pattern.first(), path.first()) {
+        (None, None) => true,
+        (Some(&"**"), _) => {
+            glob_segments(&pattern[1..], path)
+                || (!path.is_empty() && glob_segments(pattern, &path[1..]))
+        }
+        (Some(p), Some(s)) => {
+            glob(p.as_bytes(), s.as_bytes()) && glob_segments(&pattern[1..], &path[1..])
+        }
+        _ => false,
+    }
+}
+
+fn glob(pattern: &[u8], name: &[u8]) -> bool {
+    match (pattern.first(), name.first()) {
+        (None, None) => true,
+        (Some(b'*'), _) => {
+            glob(&pattern[1..], name) || (!name.is_empty() && glob(pattern, &name[1..]))
+        }
+        (Some(b'?'), Some(_)) => glob(&pattern[1..], &name[1..]),
+        (Some(p), Some(n)) => p == n && glob(&pattern[1..], &name[1..]),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wildcards() {
+        assert!(matches("*.py", "pkg/mod.py"));
+        assert!(matches("*.py", ".py"));
+        assert!(!matches("*.py", "pkg/mod.pyc"));
+        assert!(matches("mod?.py", "mod1.py"));
+        assert!(!matches("mod?.py", "mod.py"));
+        assert!(!matches("pkg/*.py", "pkg/sub/mod.py"));
+        assert!(!matches("pkg?mod.py", "pkg/mod.py"));
+    }
+
+    #[test]
+    fn double_star() {
+        assert!(matches("docs/**", "docs/a/b.md"));
+        assert!(matches("**/data/*.csv", "data/x.csv"));
+        assert!(matches("**/data/*.csv", "pkg/sub/data/x.csv"));
+        assert!(matches("pkg/**/mod.py", "pkg/mod.py"));
+        assert!(!matches("pkg/**/mod.py", "other/mod.py"));
+    }
+
+    #[test]
+    fn anchoring() {
+        // Patterns with a `/` are relative to the PYTHONPATH entry
+        assert!(matches("pkg/mod.py", "pkg/mod.py"));
+        assert!(matches("/pkg/mod.py", "pkg/mod.py"));
+        assert!(!matches("pkg/mod.py", "vendor/pkg/mod.py"));
+        // Other patterns match the name at any depth
+        assert!(matches("tests", "pkg/tests"));
+        assert!(!matches("tests", "pkg/tests.py"));
+    }
+
+    #[test]
+    fn excludes() {
+        let filter = SourceFilter::new(vec!["*.py".into()], vec!["secret.py".into()], true);
+        assert!(filter.is_excluded("pkg/secret.py"));
+        assert!(filter.is_excluded(".env"));
+        assert!(filter.is_excluded("pkg/__pycache__"));
+        assert!(!filter.is_excluded("pkg/mod.py"));
+        assert!(filter.is_included("pkg/mod.py"));
+        assert!(!filter.is_included("pkg/data.json"));
+
+        let filter = SourceFilter::new(vec![], vec![], false);
+        assert!(filter.is_empty());
+        assert!(filter.is_included("data.json"));
+        assert!(!filter.is_excluded(".env"));
+    }
+
+    #[test]
+    fn stage_files() {
+        let src = tempfile::TempDir::new().unwrap();
+        let dest = tempfile::TempDir::new().unwrap();
+        std::fs::create_dir_all(src.path().join("pkg/tests")).unwrap();
+        std::fs::write(src.path().join("pkg/mod.py"), "").unwrap();
+        std::fs::write(src.path().join("pkg/data.json"), "").unwrap();
+        std::fs::write(src.path().join("pkg/tests/test_mod.py"), "").unwrap();
+        #[cfg(unix)]
+        std::os::unix::fs::symlink(src.path(), src.path().join("pkg/cycle")).unwrap();
+
+        let filter = SourceFilter::new(vec!["*.py".into()], vec![], true);
+        filter.stage(src.path(), dest.path()).unwrap();
+        assert!(dest.path().join("pkg/mod.py").exists());
+        assert!(!dest.path().join("pkg/data.json").exists());
+        assert!(!dest.path().join("pkg/tests").exists());
+        assert!(!dest.path().join("pkg/cycle/pkg").exists());
+
+        let staging = Staging::new().unwrap();
+        let staged = staging.get(src.path(), &filter).unwrap();
+        assert!(staged.join("pkg/mod.py").exists());
+        assert_eq!(staging.get(src.path(), &filter).unwrap(), staged);
+        let unfiltered = SourceFilter::new(vec![], vec![], false);
+        assert_eq!(staging.get(src.path(), &unfiltered).unwrap(), src.path());
+    }
+}
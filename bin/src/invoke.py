@@ -1,30 +1,133 @@
-import traceback
+import sys
+
+__dispatch = []
+
+
+def __verify_exports(names):
+    import extism
+
+    global __dispatch
+    registered = extism.__exports
+    missing = [name for name in names if name not in registered]
+    if missing:
+        raise Exception(
+            f"Exports {', '.join(missing)} were found in the source but never registered, "
+            "make sure the @extism.plugin_fn/@extism.shared_fn decorators run at import time"
+        )
+
+    unexpected = [name for name in registered if name not in names]
+    if unexpected:
+        raise Exception(
+            f"Exports {', '.join(unexpected)} were registered but not found in the source, "
+            "exported functions must be defined at the top level of the plugin module"
+        )
+
+    __dispatch = [registered[name] for name in names]
+
+
+def __run_hooks(hooks, *args, error=None):
+    """Call every hook, when the export raised `error` a failing hook is logged so it
+    doesn't replace the export's exception, otherwise the first failure is raised
+    once all hooks have run"""
+    import extism
+
+    failure = None
+    for hook in hooks:
+        try:
+            hook(*args)
+        except Exception as exc:
+            if error is None and failure is None:
+                failure = exc
+                continue
+            name = getattr(hook, "__qualname__", repr(hook))
+            extism.log(
+                extism.LogLevel.Error,
+                f"Hook {name} failed: {type(exc).__name__}: {exc}",
+            )
+    if failure is not None:
+        raise failure
 
 
 def __invoke(index, shared, *args):
     import extism
 
+    # Exceptions are formatted by `extism._format_error` when they reach the core
+    f = __dispatch[index]
+    name = f.__name__
+
+    error = None
     try:
-        f = extism.__exports[index]
-
-        if shared:
-            a = []
-            argnames = f.__code__.co_varnames
-            for i, arg in enumerate(args):
-                t = f.__annotations__.get(argnames[i], extism.memory.MemoryHandle)
-                a.append(extism._load(t, arg))
-        else:
-            a = [extism._store(x) for x in args]
-
-        res = f(*a)
-        if shared and res is not None:
-            return extism._store(res)
-        if res is not None and "return" in f.__annotations__:
-            return extism._load(f.__annotations__["return"], res)
-        else:
-            return res
+        with extism._Limits.from_config():
+            for hook in extism.__before_call:
+                hook(name)
+
+            if shared:
+                a = []
+                argnames = f.__code__.co_varnames
+                for i, arg in enumerate(args):
+                    t = f.__annotations__.get(argnames[i], extism.memory.MemoryHandle)
+                    a.append(extism._load(t, arg))
+            else:
+                a = [extism._store(x) for x in args]
+
+            res = f(*a)
+            if shared and res is not None:
+                return extism._store(res)
+            if res is not None and "return" in f.__annotations__:
+                return extism._load(f.__annotations__["return"], res)
+            else:
+                return res
     except BaseException as exc:
-        tb = "".join(traceback.format_tb(exc.__traceback__))
-        err = f"{str(exc)}:\n{tb}"
-        extism.ffi.set_error(err)
-        raise exc
+        error = exc
+        __run_hooks(extism.__call_error, name, exc, error=exc)
+        raise
+    finally:
+        try:
+            __run_hooks(extism.__after_call, name, error=error)
+        finally:
+            sys.stdout.flush()
+            sys.stderr.flush()
+
+
+def __trace_emit(index, n_args, dispatch_ns, python_ns, ok):
+    import extism
+    import json
+
+    event = {
+        "export": __dispatch[index].__name__ if index < len(__dispatch) else None,
+        "args": n_args,
+        "dispatch_ns": dispatch_ns,
+        "python_ns": python_ns,
+        "ok": ok,
+        "host_calls": extism._trace_host_calls,
+    }
+    extism.log(extism.LogLevel.Info, json.dumps(event))
+
+
+__command_code = None
+
+
+def __command(argv, environ):
+    import os
+    import sys
+
+    sys.argv = argv
+    os.environ.clear()
+    os.environ.update(environ)
+
+    try:
+        if __command_code is not None:
+            exec(__command_code, {"__name__": "__main__"})
+            return 0
+        res = globals()["main"]()
+        return res if isinstance(res, int) else 0
+    except SystemExit as exc:
+        if exc.code is None:
+            return 0
+        elif isinstance(exc.code, int):
+            return exc.code
+        print(exc.code, file=sys.stderr)
+        return 1
+    finally:
+        sys.stdout.flush()
+        sys.stderr.flush()
@@ -1,18 +1,27 @@
+mod config;
+mod debug_info;
+mod filter;
 mod opt;
 mod options;
 mod py;
 mod shim;
+mod size_report;
+mod vendor;
 
-use anyhow::{bail, Error};
+use anyhow::{bail, Context, Error};
 use log::LevelFilter;
-use options::Options;
+use options::{BuildFlags, BuildOptions, Options};
 use structopt::StructOpt;
 use tempfile::TempDir;
 
 use std::borrow::Cow;
+use std::collections::HashSet;
 use std::env;
-use std::io::Write;
-use std::process::{Command, Stdio};
+use std::ffi::OsString;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::atomic::{AtomicUsize, Ordering};
 
 const CORE: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/core.wasm"));
 const INVOKE: &str = include_str!("invoke.py");
@@ -33,102 +42,373 @@ struct Export {
     results: Vec<wagen::ValType>,
 }
 
-fn main() -> Result<(), Error> {
-    // Setup logging
-    let mut builder = env_logger::Builder::new();
-    builder
-        .filter(None, LevelFilter::Info)
-        .target(env_logger::Target::Stdout)
-        .init();
+/// A plugin to build and where to write it
+struct Target {
+    input: PathBuf,
+    output: PathBuf,
+    debug_info: Option<PathBuf>,
+    size_report: bool,
+}
 
-    // Parse CLI arguments
-    let opts = Options::from_args();
-    let core: Cow<[u8]> = if let Ok(path) = std::env::var("EXTISM_ENGINE_PATH") {
-        Cow::Owned(std::fs::read(path)?)
+/// Everything that's shared by the plugins built in one invocation
+struct Toolchain {
+    core: Cow<'static, [u8]>,
+    deps_dir: PathBuf,
+    python_paths: Vec<PathBuf>,
+    staging: filter::Staging,
+    // Holds the vendored dependencies
+    _tmp_dir: TempDir,
+}
+
+fn load_core() -> Result<Cow<'static, [u8]>, Error> {
+    if let Some(path) = env::var_os("EXTISM_ENGINE_PATH") {
+        Ok(Cow::Owned(std::fs::read(path)?))
     } else {
-        Cow::Borrowed(CORE)
-    };
+        Ok(Cow::Borrowed(CORE))
+    }
+}
 
-    // Generate core module if `core` flag is set
-    if opts.core {
-        opt::Optimizer::new(&core)
-            .wizen(true)
-            .debug(opts.debug)
-            .write_optimized_wasm(opts.output)?;
-        return Ok(());
+impl Toolchain {
+    fn new(flags: &BuildFlags) -> Result<Self, Error> {
+        for item in &flags.config {
+            if !item.contains('=') || item.contains('\n') {
+                bail!("Invalid config `{item}`, expected a single line `key=value` pair");
+            }
+        }
+
+        let core = load_core()?;
+        let deps_dir = opt::find_deps(flags.deps_dir.as_deref())?;
+        let python_version = opt::check_deps_version(&core, &deps_dir)?;
+        opt::check_binaryen()?;
+
+        // Vendored dependencies are staged in the temp dir and searched before PYTHONPATH
+        let tmp_dir = TempDir::new()?;
+        let mut python_paths: Vec<PathBuf> = env::var_os("PYTHONPATH")
+            .map(|x| env::split_paths(&x).collect())
+            .unwrap_or_default();
+        if let (Some(requirements), Some(wheelhouse)) = (&flags.requirements, &flags.wheelhouse) {
+            let site_packages = tmp_dir.path().join("site-packages");
+            vendor::vendor(requirements, wheelhouse, &site_packages, &python_version)?;
+            python_paths.insert(0, site_packages);
+        }
+
+        Ok(Toolchain {
+            core,
+            deps_dir,
+            python_paths,
+            staging: filter::Staging::new()?,
+            _tmp_dir: tmp_dir,
+        })
     }
 
-    let mut user_code = std::fs::read_to_string(&opts.input_py)?;
-    user_code.push('\n');
-    user_code += INVOKE;
+    fn build(&self, flags: &BuildFlags, target: &Target) -> Result<(), Error> {
+        let source = std::fs::read_to_string(&target.input)?;
+        let (imports, exports) = py::find_imports_and_exports(&source)?;
+        if exports.is_empty() && !flags.command {
+            anyhow::bail!(
+                "No exports found, use the @extism.plugin_fn decorator to specify exported functions"
+            )
+        }
 
-    let tmp_dir = TempDir::new()?;
-    let core_path = tmp_dir.path().join("core.wasm");
-    let shim_path = tmp_dir.path().join("shim.wasm");
+        let config = config::load(&target.input)?;
 
-    let self_cmd = env::args().next().expect("Expected a command argument");
-    {
-        let mut command = Command::new(self_cmd);
-        command
-            .arg("-c")
-            .arg(&opts.input_py)
-            .arg("-o")
-            .arg(&core_path);
+        let input_name = target.input.display().to_string();
+        let mut sources = debug_info::core_sources();
+        let user_code = if flags.command && !py::has_main(&source)? {
+            if !exports.is_empty() {
+                bail!("Exported functions require a main() function when building with --command");
+            }
+
+            // Without a `main` function the module body is the program, so it's compiled
+            // during initialization but only executed from `_start`
+            let hex = source.bytes().fold(String::new(), |mut hex, b| {
+                let _ = std::fmt::Write::write_fmt(&mut hex, format_args!("{b:02x}"));
+                hex
+            });
+            sources.push(debug_info::Source::new(
+                "<command>",
+                &input_name,
+                1,
+                &source,
+            ));
+            sources.push(debug_info::Source::new("<source>", "invoke.py", 1, INVOKE));
+            format!(
+                "{INVOKE}\n__command_code = compile(bytes.fromhex(\"{hex}\").decode(), \"<command>\", \"exec\")\n"
+            )
+        } else {
+            let invoke_start = source.matches('\n').count() + 2;
+            sources.push(debug_info::Source::new("<source>", &input_name, 1, &source));
+            sources.push(debug_info::Source::new(
+                "<source>",
+                "invoke.py",
+                invoke_start,
+                INVOKE,
+            ));
+            format!("{source}\n{INVOKE}")
+        };
+
+        let tmp_dir = TempDir::new()?;
+        let core_path = tmp_dir.path().join("core.wasm");
+        let shim_path = tmp_dir.path().join("shim.wasm");
+
+        // Only these variables are visible to the core while it initializes the plugin
+        let mut env: Vec<(OsString, OsString)> = vec![];
+        let mut set = |name: &str, value: &str| env.push((name.into(), value.into()));
+        for name in &flags.allow_env {
+            if let Ok(value) = env::var(name) {
+                set(name, &value);
+            }
+        }
+        if flags.reproducible {
+            if let Ok(epoch) = env::var("SOURCE_DATE_EPOCH") {
+                set("SOURCE_DATE_EPOCH", &epoch);
+            }
+        }
+
+        // The core verifies its export registry against these names during
+        // initialization, so the shim's indices always refer to the right function
+        let export_names: Vec<&str> = exports.iter().map(|e| e.name.as_str()).collect();
+        set("EXTISM_PYTHON_EXPORTS", &export_names.join(","));
+        if flags.trace_calls {
+            set("EXTISM_PYTHON_TRACE_CALLS", "1");
+        }
+        if flags.error_format != "text" {
+            set("EXTISM_PYTHON_ERROR_FORMAT", &flags.error_format);
+        }
+        if flags.error_detail != "traceback" {
+            set("EXTISM_PYTHON_ERROR_DETAIL", &flags.error_detail);
+        }
+        if flags.tree_shake {
+            set("EXTISM_PYTHON_TREE_SHAKE", "1");
+        }
+        if flags.precompile {
+            set("EXTISM_PYTHON_PRECOMPILE", "1");
+        }
+        if !flags.config.is_empty() {
+            set("EXTISM_PYTHON_BUILD_CONFIG", &flags.config.join("\n"));
+        }
+
+        let preload: Vec<&str> = config
+            .preload
+            .iter()
+            .chain(&flags.preload)
+            .map(|x| x.as_str())
+            .collect();
+        if !preload.is_empty() {
+            set("EXTISM_PYTHON_PRELOAD", &preload.join(","));
+        }
+
+        // print() and sys.stderr are forwarded to the Extism log unless WASI stdio
+        // is kept, commands always write to WASI stdio
+        if !(flags.command || flags.wasi_stdio || config.wasi_stdio == Some(true)) {
+            let stdout_level = flags.stdout_level.as_ref().or(config.stdout_level.as_ref());
+            let stderr_level = flags.stderr_level.as_ref().or(config.stderr_level.as_ref());
+            set(
+                "EXTISM_PYTHON_STDOUT_LEVEL",
+                stdout_level.map_or("info", |x| x.as_str()),
+            );
+            set(
+                "EXTISM_PYTHON_STDERR_LEVEL",
+                stderr_level.map_or("error", |x| x.as_str()),
+            );
+        }
 
-        command.env_clear();
+        // Command line filters are added to the ones from pyproject.toml
+        let filter = filter::SourceFilter::new(
+            config
+                .include
+                .iter()
+                .chain(&flags.include)
+                .cloned()
+                .collect(),
+            config
+                .exclude
+                .iter()
+                .chain(&flags.exclude)
+                .cloned()
+                .collect(),
+            !(flags.no_default_excludes || config.default_excludes == Some(false)),
+        );
 
-        if opts.debug {
-            command.arg("-g");
+        opt::Optimizer::new(&self.core)
+            .wizen(true)
+            .debug(flags.debug)
+            .deps_dir(Some(self.deps_dir.clone()))
+            .reproducible(flags.reproducible)
+            .size_report(target.size_report)
+            .filter(filter)
+            .source(&user_code)
+            .env(env)
+            .python_path(self.python_paths.clone())
+            .staging(&self.staging)
+            .write_optimized_wasm(&core_path)
+            .with_context(|| format!("Couldn't initialize {}", target.input.display()))?;
+
+        shim::generate(&exports, &imports, flags.command, &shim_path)?;
+
+        // Merge the shim with the core module
+        let mut cmd = Command::new("wasm-merge");
+        cmd.arg(&core_path)
+            .arg("core")
+            .arg(&shim_path)
+            .arg("shim")
+            .arg("-o")
+            .arg(&target.output)
+            .arg("--enable-reference-types")
+            .arg("--enable-bulk-memory");
+        // Names are needed for the debug info, they are stripped from the output after
+        // it has been written so function indices are the same in both files
+        let keep_names = flags.debug || target.debug_info.is_some();
+        if keep_names {
+            cmd.arg("-g");
         }
-        let mut command = command.stdin(Stdio::piped()).spawn()?;
-        command
-            .stdin
-            .take()
-            .expect("Expected to get writeable stdin")
-            .write_all(user_code.as_bytes())?;
-        let status = command.wait()?;
+
+        let status = cmd.status()?;
         if !status.success() {
-            bail!("Couldn't create wasm from input");
+            bail!("wasm-merge failed. Couldn't merge shim");
+        }
+
+        let unoptimized = std::fs::metadata(&target.output)?.len() as usize;
+        opt::optimize_wasm_file(&target.output, keep_names)?;
+        if let Some(debug_info) = &target.debug_info {
+            debug_info::write_sidecar(&target.output, debug_info, &sources)?;
+            if !flags.debug {
+                opt::strip_wasm_file(&target.output)?;
+            }
         }
-    }
 
-    let (imports, exports) = py::find_imports_and_exports(user_code)?;
-    if exports.is_empty() {
-        anyhow::bail!(
-            "No exports found, use the @extism.plugin_fn decorator to specify exported functions"
-        )
+        if target.size_report {
+            size_report::print(
+                &self.core,
+                &core_path,
+                &tmp_dir.path().join(size_report::MODULES_FILE),
+                unoptimized,
+                &target.output,
+            )?;
+        }
+        Ok(())
     }
+}
 
-    shim::generate(&exports, &imports, &shim_path)?;
+/// Build every input of `extism-py build`, `opts.jobs` at a time
+fn build_all(opts: BuildOptions) -> Result<(), Error> {
+    let mut targets = vec![];
+    let mut names = HashSet::new();
+    for input in &opts.inputs {
+        let Some(name) = input.file_stem().map(|x| x.to_string_lossy().to_string()) else {
+            bail!("Invalid input path: {}", input.display());
+        };
+        if !names.insert(name.clone()) {
+            bail!("More than one input would be written to {name}.wasm");
+        }
+        targets.push(Target {
+            input: input.clone(),
+            output: opts.out_dir.join(format!("{name}.wasm")),
+            debug_info: opts
+                .debug_info_dir
+                .as_ref()
+                .map(|dir| dir.join(format!("{name}.debug.wasm"))),
+            size_report: false,
+        });
+    }
 
-    let output = Command::new("wasm-merge")
-        .arg("--version")
-        .stdout(Stdio::null())
-        .stderr(Stdio::null())
-        .status();
-    if output.is_err() {
-        bail!("Failed to detect wasm-merge. Please install binaryen and make sure wasm-merge is on your path: https://github.com/WebAssembly/binaryen");
+    std::fs::create_dir_all(&opts.out_dir)?;
+    if let Some(dir) = &opts.debug_info_dir {
+        std::fs::create_dir_all(dir)?;
     }
 
-    // Merge the shim with the core module
-    let mut cmd = Command::new("wasm-merge");
-    cmd.arg(&core_path)
-        .arg("core")
-        .arg(&shim_path)
-        .arg("shim")
-        .arg("-o")
-        .arg(&opts.output)
-        .arg("--enable-reference-types")
-        .arg("--enable-bulk-memory");
-    if opts.debug {
-        cmd.arg("-g");
+    let toolchain = Toolchain::new(&opts.flags)?;
+    let jobs = opts
+        .jobs
+        .unwrap_or_else(|| std::thread::available_parallelism().map_or(1, |n| n.get()))
+        .clamp(1, targets.len());
+    let next = AtomicUsize::new(0);
+    let failed: Vec<&Path> = std::thread::scope(|scope| {
+        let workers: Vec<_> = (0..jobs)
+            .map(|_| {
+                scope.spawn(|| {
+                    let mut failed = vec![];
+                    while let Some(target) = targets.get(next.fetch_add(1, Ordering::Relaxed)) {
+                        log::info!("Building {}", target.output.display());
+                        if let Err(err) = toolchain.build(&opts.flags, target) {
+                            log::error!("Failed to build {}: {err:#}", target.input.display());
+                            failed.push(target.input.as_path());
+                        }
+                    }
+                    failed
+                })
+            })
+            .collect();
+        workers
+            .into_iter()
+            .flat_map(|worker| worker.join().expect("Build worker panicked"))
+            .collect()
+    });
+
+    if !failed.is_empty() {
+        let failed: Vec<String> = failed.iter().map(|x| x.display().to_string()).collect();
+        bail!(
+            "{} of {} plugins failed to build: {}",
+            failed.len(),
+            targets.len(),
+            failed.join(", ")
+        );
     }
+    Ok(())
+}
+
+fn main() -> Result<(), Error> {
+    // Setup logging
+    let mut builder = env_logger::Builder::new();
+    builder
+        .filter(None, LevelFilter::Info)
+        .target(env_logger::Target::Stdout)
+        .init();
 
-    let status = cmd.status()?;
-    if !status.success() {
-        bail!("wasm-merge failed. Couldn't merge shim");
+    // Parse CLI arguments
+    let opts = Options::from_args();
+    let input_py = match opts.command {
+        Some(options::Command::Symbolize(opts)) => return debug_info::symbolize(&opts.debug_info),
+        Some(options::Command::Build(opts)) => return build_all(*opts),
+        None => opts.input_py.unwrap_or_else(|| {
+            structopt::clap::Error::with_description(
+                "The input file is required",
+                structopt::clap::ErrorKind::MissingRequiredArgument,
+            )
+            .exit()
+        }),
+    };
+
+    // Generate core module if `core` flag is set, the plugin source is read from stdin
+    if opts.core {
+        let core = load_core()?;
+        let mut source = String::new();
+        std::io::stdin().read_to_string(&mut source)?;
+        opt::Optimizer::new(&core)
+            .wizen(true)
+            .source(&source)
+            .env(env::vars_os().collect())
+            .debug(opts.flags.debug)
+            .deps_dir(opts.flags.deps_dir)
+            .reproducible(opts.flags.reproducible)
+            .size_report(opts.size_report)
+            .filter(filter::SourceFilter::new(
+                opts.flags.include,
+                opts.flags.exclude,
+                !opts.flags.no_default_excludes,
+            ))
+            .write_optimized_wasm(opts.output)?;
+        return Ok(());
     }
 
-    opt::optimize_wasm_file(opts.output, opts.debug)?;
-    Ok(())
+    let toolchain = Toolchain::new(&opts.flags)?;
+    toolchain.build(
+        &opts.flags,
+        &Target {
+            input: input_py,
+            output: opts.output,
+            debug_info: opts.debug_info,
+            size_report: opts.size_report,
+        },
+    )
 }
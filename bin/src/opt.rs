@@ -1,46 +1,114 @@
 use anyhow::{Error, Result};
 use std::{
+    ffi::OsString,
     path::{Path, PathBuf},
     process::{Command, Stdio},
 };
 use wizer::Wizer;
 
+use crate::filter::{SourceFilter, Staging};
+
 pub(crate) struct Optimizer<'a> {
     wizen: bool,
     optimize: bool,
     wasm: &'a [u8],
     debug: bool,
+    deps_dir: Option<PathBuf>,
+    reproducible: bool,
+    size_report: bool,
+    filter: SourceFilter,
+    source: &'a str,
+    env: Vec<(OsString, OsString)>,
+    python_path: Option<Vec<PathBuf>>,
+    staging: Option<&'a Staging>,
 }
 
-fn find_deps() -> PathBuf {
-    if let Ok(path) = std::env::var("EXTISM_PYTHON_WASI_DEPS_DIR") {
-        return PathBuf::from(path);
+pub(crate) fn find_deps(deps_dir: Option<&Path>) -> Result<PathBuf, Error> {
+    if let Some(path) = deps_dir {
+        if !path.exists() {
+            anyhow::bail!(
+                "wasi-deps path passed to --deps-dir doesn't exist: {}",
+                path.display()
+            );
+        }
+        return Ok(path.to_path_buf());
     }
 
-    let in_repo = PathBuf::from("../lib/target/wasm32-wasi/wasi-deps");
-    if in_repo.exists() {
-        return in_repo;
+    if let Ok(path) = std::env::var("EXTISM_PYTHON_WASI_DEPS_DIR") {
+        let path = PathBuf::from(path);
+        if !path.exists() {
+            anyhow::bail!(
+                "wasi-deps path set in EXTISM_PYTHON_WASI_DEPS_DIR doesn't exist: {}",
+                path.display()
+            );
+        }
+        return Ok(path);
     }
 
-    let in_repo_root = PathBuf::from("lib/target/wasm32-wasi/wasi-deps");
-    if in_repo_root.exists() {
-        return in_repo_root;
+    let mut searched = vec![
+        PathBuf::from("../lib/target/wasm32-wasi/wasi-deps"),
+        PathBuf::from("lib/target/wasm32-wasi/wasi-deps"),
+        PathBuf::from("/usr/local/share/extism-py"),
+        PathBuf::from("/usr/share/extism-py"),
+    ];
+    if let Some(dirs) = directories::BaseDirs::new() {
+        searched.push(dirs.data_dir().join("extism-py"));
     }
 
-    let usr_local_share = PathBuf::from("/usr/local/share/extism-py");
-    if usr_local_share.exists() {
-        return usr_local_share;
+    if let Some(path) = searched.iter().find(|path| path.exists()) {
+        return Ok(path.clone());
     }
 
-    let usr_share = PathBuf::from("/usr/share/extism-py");
-    if usr_share.exists() {
-        return usr_share;
+    let searched: Vec<String> = searched
+        .iter()
+        .map(|path| format!("  {}", path.display()))
+        .collect();
+    anyhow::bail!(
+        "Unable to find wasi-deps, searched:\n{}\nReinstall extism-py or pass the location with --deps-dir",
+        searched.join("\n")
+    )
+}
+
+// Custom section of the core holding the CPython version it links
+const PYTHON_VERSION_SECTION: &str = "extism-py-python-version";
+
+/// Make sure the standard library in `deps_dir` belongs to the CPython version linked
+/// into `core`, before any plugin is built, returns that version
+pub(crate) fn check_deps_version(core: &[u8], deps_dir: &Path) -> Result<String, Error> {
+    let stamp = deps_dir.join("python-version");
+    let deps = match std::fs::read_to_string(&stamp) {
+        Ok(version) => version.trim().to_string(),
+        Err(err) => anyhow::bail!(
+            "Unable to read the CPython version of the wasi-deps from {}: {err}, \
+             use the wasi-deps from the same extism-py release",
+            stamp.display()
+        ),
+    };
+
+    let mut linked = None;
+    for payload in wagen::parser::Parser::new(0).parse_all(core) {
+        if let wagen::parser::Payload::CustomSection(reader) = payload? {
+            if reader.name() == PYTHON_VERSION_SECTION {
+                linked = Some(String::from_utf8_lossy(reader.data()).trim().to_string());
+            }
+        }
     }
+    let core = match linked {
+        Some(version) if !version.is_empty() => version,
+        _ => anyhow::bail!(
+            "The engine doesn't record the CPython version it was built with, \
+             rebuild it together with its wasi-deps"
+        ),
+    };
 
-    directories::BaseDirs::new()
-        .unwrap()
-        .data_dir()
-        .join("extism-py")
+    if core != deps {
+        anyhow::bail!(
+            "wasi-deps at {} provide CPython {deps} but the engine was built against CPython {core}, \
+             use the wasi-deps from the same extism-py release or pass --deps-dir",
+            deps_dir.display()
+        );
+    }
+    Ok(core)
 }
 
 impl<'a> Optimizer<'a> {
@@ -50,6 +118,14 @@ impl<'a> Optimizer<'a> {
             optimize: false,
             wizen: false,
             debug: false,
+            deps_dir: None,
+            reproducible: false,
+            size_report: false,
+            filter: SourceFilter::default(),
+            source: "",
+            env: vec![],
+            python_path: None,
+            staging: None,
         }
     }
 
@@ -67,37 +143,108 @@ impl<'a> Optimizer<'a> {
         Self { wizen, ..self }
     }
 
+    pub fn deps_dir(self, deps_dir: Option<PathBuf>) -> Self {
+        Self { deps_dir, ..self }
+    }
+
+    pub fn reproducible(self, reproducible: bool) -> Self {
+        Self {
+            reproducible,
+            ..self
+        }
+    }
+
     #[cfg(target_os = "windows")]
     fn convert_windows_paths(&self, paths: Vec<(String, PathBuf)>) -> Vec<(String, PathBuf)> {
         use std::path::Component;
         let mut ret = vec![];
         for (_, path) in paths {
-            let new_path = 
-                path.components()
-                    .filter_map(|comp| match comp {
-                        Component::Normal(part) => Some(part.to_string_lossy().to_string()),
-                        _ => None, // Skip root, prefix, or other non-normal components
-                    })
-                    .collect::<Vec<_>>()
-                    .join("/");
+            let new_path = path
+                .components()
+                .filter_map(|comp| match comp {
+                    Component::Normal(part) => Some(part.to_string_lossy().to_string()),
+                    _ => None, // Skip root, prefix, or other non-normal components
+                })
+                .collect::<Vec<_>>()
+                .join("/");
             let normalized = format!("/{}", new_path);
             ret.push((normalized, path));
         }
         ret
     }
 
+    pub fn size_report(self, size_report: bool) -> Self {
+        Self {
+            size_report,
+            ..self
+        }
+    }
+
+    pub fn filter(self, filter: SourceFilter) -> Self {
+        Self { filter, ..self }
+    }
+
+    /// The plugin source the core compiles during initialization
+    pub fn source(self, source: &'a str) -> Self {
+        Self { source, ..self }
+    }
+
+    /// The environment visible to the core during initialization
+    pub fn env(self, env: Vec<(OsString, OsString)>) -> Self {
+        Self { env, ..self }
+    }
+
+    /// Directories searched for modules, defaults to `PYTHONPATH`
+    pub fn python_path(self, python_path: Vec<PathBuf>) -> Self {
+        Self {
+            python_path: Some(python_path),
+            ..self
+        }
+    }
+
+    /// Reuse the directories staged for other plugins
+    pub fn staging(self, staging: &'a Staging) -> Self {
+        Self {
+            staging: Some(staging),
+            ..self
+        }
+    }
+
     pub fn write_optimized_wasm(self, dest: impl AsRef<Path>) -> Result<(), Error> {
-        let python_path = std::env::var("PYTHONPATH").unwrap_or_else(|_| String::from("."));
-        let split_paths = std::env::split_paths(&python_path);
-        let paths: Vec<(String, PathBuf)> = split_paths.map(|p| (p.to_string_lossy().to_string(), p)).collect();
-        
+        let python_path = self.python_path.clone().unwrap_or_else(|| {
+            let python_path = std::env::var_os("PYTHONPATH").unwrap_or_else(|| ".".into());
+            std::env::split_paths(&python_path).collect()
+        });
+        let paths: Vec<(String, PathBuf)> = python_path
+            .into_iter()
+            .map(|p| (p.to_string_lossy().to_string(), p))
+            .collect();
+
         #[cfg(target_os = "windows")]
         let paths = self.convert_windows_paths(paths);
-        #[cfg(target_os = "windows")]
-        std::env::set_var("PYTHONPATH", paths.iter().map(|p| p.0.clone()).collect::<Vec<_>>().join(":"));
+
+        // Host paths end up in the snapshot through module and code object filenames,
+        // so they are mapped to guest paths that don't depend on the build machine
+        let mut env = self.env.clone();
+        let paths = if self.reproducible {
+            let paths: Vec<(String, PathBuf)> = paths
+                .into_iter()
+                .enumerate()
+                .map(|(i, (_, path))| (format!("/python/{i}"), path))
+                .collect();
+            env.push(("PYTHONHASHSEED".into(), "0".into()));
+            env.push(("EXTISM_PYTHON_REPRODUCIBLE".into(), "1".into()));
+            paths
+        } else {
+            paths
+        };
+        let guest_paths: Vec<&str> = paths.iter().map(|p| p.0.as_str()).collect();
+        env.push(("PYTHONPATH".into(), guest_paths.join(":").into()));
+
+        let deps_root = find_deps(self.deps_dir.as_deref())?;
 
         // Ensure compatibility with old releases
-        let mut deps = find_deps().join("usr");
+        let mut deps = deps_root.join("usr");
         if !deps.exists() {
             let parent = deps.parent().unwrap();
             if parent.join("local").exists() {
@@ -111,15 +258,50 @@ impl<'a> Optimizer<'a> {
             let mut w = Wizer::new();
             w.allow_wasi(true)?
                 .inherit_stdio(true)
-                .inherit_env(true)
+                .inherit_env(false)
                 .wasm_bulk_memory(true)
                 .map_dir("/usr", deps);
+
+            // Only the files that pass the filter are visible during initialization, the
+            // directories are staged to keep tests and secrets out of the snapshot
+            let local;
+            let staging = match self.staging {
+                Some(staging) => staging,
+                None => {
+                    local = Staging::new()?;
+                    &local
+                }
+            };
             for (mapped, path) in paths {
-                if !path.exists() {
+                if !path.is_dir() {
                     continue;
                 }
-                w.map_dir(mapped, path);
+                w.map_dir(mapped, staging.get(&path, &self.filter)?);
+            }
+
+            // The core writes the memory retained by each Python module next to the
+            // initialized module
+            if self.size_report {
+                let dir = dest.as_ref().parent().unwrap_or(Path::new(""));
+                let dir = if dir.as_os_str().is_empty() {
+                    Path::new(".")
+                } else {
+                    dir
+                };
+                w.map_dir("/extism-py-report", dir);
+                env.push((
+                    "EXTISM_PYTHON_SIZE_REPORT".into(),
+                    format!("/extism-py-report/{}", crate::size_report::MODULES_FILE).into(),
+                ));
             }
+
+            // The core reads its environment and the plugin source from the build
+            // directory, wizer can only pass the ones of this process
+            let build_dir = tempfile::TempDir::new()?;
+            std::fs::write(build_dir.path().join("env"), encode_env(&env))?;
+            std::fs::write(build_dir.path().join("source.py"), self.source)?;
+            w.map_dir("/extism-py-build", build_dir.path());
+
             let wasm = w.run(self.wasm)?;
             std::fs::write(&dest, wasm)?;
         } else {
@@ -134,15 +316,34 @@ impl<'a> Optimizer<'a> {
     }
 }
 
-pub(crate) fn optimize_wasm_file(dest: impl AsRef<Path>, debug: bool) -> Result<(), Error> {
-    let output = Command::new("wasm-opt")
-        .arg("--version")
-        .stdout(Stdio::null())
-        .stderr(Stdio::null())
-        .status();
-    if output.is_err() {
-        anyhow::bail!("Failed to detect wasm-opt. Please install binaryen and make sure wasm-opt is on your path: https://github.com/WebAssembly/binaryen");
+/// `NUL` separated `KEY=VALUE` pairs, as read by the core
+fn encode_env(env: &[(OsString, OsString)]) -> Vec<u8> {
+    let mut out = vec![];
+    for (key, value) in env {
+        out.extend_from_slice(key.as_encoded_bytes());
+        out.push(b'=');
+        out.extend_from_slice(value.as_encoded_bytes());
+        out.push(0);
+    }
+    out
+}
+
+/// Make sure `wasm-merge` and `wasm-opt` can be found, once before any plugin is built
+pub(crate) fn check_binaryen() -> Result<(), Error> {
+    for tool in ["wasm-merge", "wasm-opt"] {
+        let output = Command::new(tool)
+            .arg("--version")
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .status();
+        if output.is_err() {
+            anyhow::bail!("Failed to detect {tool}. Please install binaryen and make sure {tool} is on your path: https://github.com/WebAssembly/binaryen");
+        }
     }
+    Ok(())
+}
+
+pub(crate) fn optimize_wasm_file(dest: impl AsRef<Path>, debug: bool) -> Result<(), Error> {
     let mut cmd = Command::new("wasm-opt");
     cmd.arg("--enable-reference-types")
         .arg("--enable-bulk-memory")
@@ -158,3 +359,21 @@ pub(crate) fn optimize_wasm_file(dest: impl AsRef<Path>, debug: bool) -> Result<
         .status()?;
     Ok(())
 }
+
+pub(crate) fn strip_wasm_file(dest: impl AsRef<Path>) -> Result<(), Error> {
+    let status = Command::new("wasm-opt")
+        .arg("--enable-reference-types")
+        .arg("--enable-bulk-memory")
+        .arg("--strip")
+        .arg(dest.as_ref())
+        .arg("-o")
+        .arg(dest.as_ref())
+        .status()?;
+    if !status.success() {
+        anyhow::bail!(
+            "wasm-opt failed. Couldn't strip {}",
+            dest.as_ref().display()
+        );
+    }
+    Ok(())
+}
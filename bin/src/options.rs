@@ -1,11 +1,20 @@
 use std::path::PathBuf;
+use structopt::clap::AppSettings;
 use structopt::StructOpt;
 
+pub const LOG_LEVELS: &[&str] = &["trace", "debug", "info", "warn", "error"];
+
+// Subcommands are only recognized as the first argument, `extism-py ./build` builds
+// an input file named `build`
 #[derive(Debug, StructOpt)]
-#[structopt(name = "extism-py", about = "Extism Python PDK compiler")]
+#[structopt(
+    name = "extism-py",
+    about = "Extism Python PDK compiler",
+    setting = AppSettings::ArgsNegateSubcommands
+)]
 pub struct Options {
     #[structopt(parse(from_os_str))]
-    pub input_py: PathBuf,
+    pub input_py: Option<PathBuf>,
 
     #[structopt(short = "o", parse(from_os_str), default_value = "index.wasm")]
     pub output: PathBuf,
@@ -13,6 +22,146 @@ pub struct Options {
     #[structopt(short = "c")]
     pub core: bool,
 
+    /// Write a debug info file for `extism-py symbolize` next to the stripped output
+    #[structopt(long = "debug-info", parse(from_os_str))]
+    pub debug_info: Option<PathBuf>,
+
+    /// Print a breakdown of the plugin size and the memory retained by each Python module
+    #[structopt(long = "size-report")]
+    pub size_report: bool,
+
+    #[structopt(flatten)]
+    pub flags: BuildFlags,
+
+    #[structopt(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Debug, StructOpt)]
+pub enum Command {
+    /// Build several plugins in parallel, each to `<out-dir>/<name>.wasm`
+    Build(Box<BuildOptions>),
+
+    /// Resolve wasm functions and Python source lines in a backtrace read from stdin
+    Symbolize(SymbolizeOptions),
+}
+
+#[derive(Debug, StructOpt)]
+pub struct BuildOptions {
+    #[structopt(parse(from_os_str), required = true)]
+    pub inputs: Vec<PathBuf>,
+
+    #[structopt(long = "out-dir", parse(from_os_str), default_value = ".")]
+    pub out_dir: PathBuf,
+
+    /// Number of plugins built at the same time, defaults to the number of CPUs
+    #[structopt(short = "j", long = "jobs")]
+    pub jobs: Option<usize>,
+
+    /// Write a debug info file for `extism-py symbolize` for each plugin to this directory
+    #[structopt(long = "debug-info-dir", parse(from_os_str))]
+    pub debug_info_dir: Option<PathBuf>,
+
+    #[structopt(flatten)]
+    pub flags: BuildFlags,
+}
+
+// Options shared by single and multi plugin builds
+#[derive(Debug, Clone, StructOpt)]
+pub struct BuildFlags {
     #[structopt(short = "g")]
     pub debug: bool,
+
+    /// Build a WASI command: `_start` runs `main()`, or the module body if there is no `main`
+    #[structopt(long = "command")]
+    pub command: bool,
+
+    /// Log a JSON event with timings and host calls for every export call
+    #[structopt(long = "trace-calls")]
+    pub trace_calls: bool,
+
+    /// Location of the wasi-deps directory containing the Python standard library
+    #[structopt(long = "deps-dir", parse(from_os_str))]
+    pub deps_dir: Option<PathBuf>,
+
+    /// Format of the errors returned to the host, `json` includes the exception type,
+    /// message, frames and error code
+    #[structopt(
+        long = "error-format",
+        default_value = "text",
+        possible_values = &["text", "json"]
+    )]
+    pub error_format: String,
+
+    /// How much of an exception is returned to the host: `message`, `type` and message,
+    /// or the full `traceback`. Redacted details are sent to the debug log instead
+    #[structopt(
+        long = "error-detail",
+        default_value = "traceback",
+        possible_values = &["message", "type", "traceback"]
+    )]
+    pub error_detail: String,
+
+    /// Keep print() and sys.stderr on WASI stdio instead of forwarding them to the Extism log
+    #[structopt(long = "wasi-stdio")]
+    pub wasi_stdio: bool,
+
+    /// Log level of the lines printed to stdout [default: info]
+    #[structopt(long = "stdout-level", possible_values = LOG_LEVELS)]
+    pub stdout_level: Option<String>,
+
+    /// Log level of the lines written to stderr [default: error]
+    #[structopt(long = "stderr-level", possible_values = LOG_LEVELS)]
+    pub stderr_level: Option<String>,
+
+    /// Drop modules that aren't reachable from the plugin code from the snapshot
+    #[structopt(long = "tree-shake")]
+    pub tree_shake: bool,
+
+    /// Compile the modules found on PYTHONPATH to bytecode stored in the snapshot
+    #[structopt(long = "precompile")]
+    pub precompile: bool,
+
+    /// Comma separated modules to import while the plugin is built, even if the plugin
+    /// only imports them inside functions
+    #[structopt(long = "preload", use_delimiter = true)]
+    pub preload: Vec<String>,
+
+    /// Produce byte-identical output for identical inputs, regardless of the build machine
+    #[structopt(long = "reproducible")]
+    pub reproducible: bool,
+
+    /// Environment variable to make available to the Python code during initialization
+    #[structopt(long = "allow-env", number_of_values = 1)]
+    pub allow_env: Vec<String>,
+
+    /// `key=value` config available to `@extism.on_init` functions while the plugin is built
+    #[structopt(long = "config", number_of_values = 1)]
+    pub config: Vec<String>,
+
+    /// Only make files matching this glob on PYTHONPATH available during initialization
+    #[structopt(long = "include", number_of_values = 1)]
+    pub include: Vec<String>,
+
+    /// Hide files matching this glob on PYTHONPATH during initialization
+    #[structopt(long = "exclude", number_of_values = 1)]
+    pub exclude: Vec<String>,
+
+    /// Don't exclude dotfiles, `__pycache__` and test directories on PYTHONPATH
+    #[structopt(long = "no-default-excludes")]
+    pub no_default_excludes: bool,
+
+    /// Pinned requirements.txt or uv.lock listing the dependencies to vendor
+    #[structopt(long = "requirements", parse(from_os_str), requires = "wheelhouse")]
+    pub requirements: Option<PathBuf>,
+
+    /// Directory of wheels the requirements are vendored from
+    #[structopt(long = "wheelhouse", parse(from_os_str), requires = "requirements")]
+    pub wheelhouse: Option<PathBuf>,
+}
+
+#[derive(Debug, StructOpt)]
+pub struct SymbolizeOptions {
+    #[structopt(parse(from_os_str))]
+    pub debug_info: PathBuf,
 }
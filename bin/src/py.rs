@@ -142,8 +142,8 @@ fn collect<R: std::fmt::Debug>(
     Ok(())
 }
 
-pub(crate) fn find_imports_and_exports(data: String) -> Result<(Vec<Import>, Vec<Export>), Error> {
-    let parsed = rustpython_parser::parse(&data, rustpython_parser::Mode::Module, "<source>")?
+pub(crate) fn find_imports_and_exports(data: &str) -> Result<(Vec<Import>, Vec<Export>), Error> {
+    let parsed = rustpython_parser::parse(data, rustpython_parser::Mode::Module, "<source>")?
         .expect_module();
 
     let mut exports = vec![];
@@ -153,3 +153,59 @@ pub(crate) fn find_imports_and_exports(data: String) -> Result<(Vec<Import>, Vec
     }
     Ok((imports, exports))
 }
+
+pub(crate) fn has_main(data: &str) -> Result<bool, Error> {
+    let parsed = rustpython_parser::parse(data, rustpython_parser::Mode::Module, "<source>")?
+        .expect_module();
+
+    let mut found = false;
+    for stmt in parsed.body {
+        if let Some(f) = stmt.as_async_function_def_stmt() {
+            if f.name.as_str() == "main" {
+                anyhow::bail!("main() can't be async when building with --command");
+            }
+        } else if let Some(f) = stmt.as_function_def_stmt() {
+            if f.name.as_str() != "main" {
+                continue;
+            }
+
+            let required = f
+                .args
+                .posonlyargs
+                .iter()
+                .chain(&f.args.args)
+                .chain(&f.args.kwonlyargs)
+                .filter(|arg| arg.default.is_none())
+                .map(|arg| arg.def.arg.as_str())
+                .collect::<Vec<_>>();
+            if !required.is_empty() {
+                anyhow::bail!(
+                    "main() is called without arguments when building with --command, but it requires: {}",
+                    required.join(", ")
+                );
+            }
+            found = true;
+        }
+    }
+
+    Ok(found)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn main_without_required_arguments() {
+        assert!(!has_main("print('hello')").unwrap());
+        assert!(has_main("def main():\n    pass").unwrap());
+        assert!(has_main("def main(name='world', *args, verbose=False, **kw):\n    pass").unwrap());
+    }
+
+    #[test]
+    fn main_with_required_arguments() {
+        let err = has_main("def main(name, *, verbose):\n    pass").unwrap_err();
+        assert!(err.to_string().contains("name, verbose"));
+        assert!(has_main("async def main():\n    pass").is_err());
+    }
+}
@@ -4,6 +4,7 @@ use wagen::{Instr, ValType};
 pub(crate) fn generate(
     exports: &[Export],
     imports: &[Import],
+    command: bool,
     shim_path: &std::path::Path,
 ) -> Result<(), Error> {
     let mut module = wagen::Module::new();
@@ -46,6 +47,8 @@ pub(crate) fn generate(
         [wagen::ValType::I32],
     );
 
+    let __command = command.then(|| module.import("core", "__command", None, [], []));
+
     let mut import_elements = Vec::new();
     for import in imports.iter() {
         let index = module.import(
@@ -152,6 +155,11 @@ pub(crate) fn generate(
         }
     }
 
+    if let Some(__command) = __command {
+        let start = module.func("_start", [], [], []).export("_start");
+        start.builder().push(Instr::Call(__command.index()));
+    }
+
     module.validate_save(shim_path)?;
     Ok(())
 }
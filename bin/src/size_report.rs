@@ -0,0 +1,116 @@
+use anyhow::{Context, Error};
+use std::path::Path;
+use wagen::parser::{Parser, Payload};
+
+/// Written by the core next to the initialized module when built with `--size-report`
+pub(crate) const MODULES_FILE: &str = "size-report-modules.tsv";
+
+// Number of Python modules listed before the rest are summarized
+const TOP_MODULES: usize = 25;
+
+#[derive(Debug, Default)]
+struct Sections {
+    code: usize,
+    data: usize,
+    custom: usize,
+    total: usize,
+}
+
+impl Sections {
+    fn read(wasm: &[u8]) -> Result<Self, Error> {
+        let mut sections = Sections {
+            total: wasm.len(),
+            ..Default::default()
+        };
+        for payload in Parser::new(0).parse_all(wasm) {
+            match payload? {
+                Payload::CodeSectionStart { range, .. } => sections.code += range.len(),
+                Payload::DataSection(reader) => sections.data += reader.range().len(),
+                Payload::CustomSection(reader) => sections.custom += reader.range().len(),
+                _ => (),
+            }
+        }
+        Ok(sections)
+    }
+
+    fn other(&self) -> usize {
+        self.total - self.code - self.data - self.custom
+    }
+}
+
+fn human(bytes: usize) -> String {
+    if bytes < 1024 {
+        return format!("{bytes} B");
+    }
+    let kib = bytes as f64 / 1024.0;
+    if kib < 1024.0 {
+        format!("{kib:.1} KiB")
+    } else {
+        format!("{:.1} MiB", kib / 1024.0)
+    }
+}
+
+fn read_modules(path: &Path) -> Result<Vec<(String, usize)>, Error> {
+    let data = std::fs::read_to_string(path)
+        .with_context(|| format!("Unable to read module sizes from {}", path.display()))?;
+    let mut modules = vec![];
+    for line in data.lines() {
+        let Some((name, size)) = line.split_once('\t') else {
+            anyhow::bail!("Invalid module size entry: {line}");
+        };
+        modules.push((name.to_string(), size.parse()?));
+    }
+    Ok(modules)
+}
+
+/// Print the size breakdown of `output`
+///
+/// `core` is the module before initialization and `snapshot` the initialized core,
+/// their data sections differ by the heap captured by wizer. `unoptimized` is the
+/// size of the merged module before `wasm-opt` ran.
+pub(crate) fn print(
+    core: &[u8],
+    snapshot: &Path,
+    modules: &Path,
+    unoptimized: usize,
+    output: &Path,
+) -> Result<(), Error> {
+    let core = Sections::read(core)?;
+    let snapshot = Sections::read(&std::fs::read(snapshot)?)?;
+    let sections = Sections::read(&std::fs::read(output)?)?;
+    let modules = read_modules(modules)?;
+
+    println!(
+        "Size report for {} ({})",
+        output.display(),
+        human(sections.total)
+    );
+    println!("  code                {:>12}", human(sections.code));
+    println!("  data                {:>12}", human(sections.data));
+    println!(
+        "    snapshot heap     {:>12}",
+        human(snapshot.data.saturating_sub(core.data))
+    );
+    println!("  custom sections     {:>12}", human(sections.custom));
+    println!("  other               {:>12}", human(sections.other()));
+    println!(
+        "  wasm-opt saved      {:>12} ({} before optimization)",
+        human(unoptimized.saturating_sub(sections.total)),
+        human(unoptimized)
+    );
+
+    println!();
+    println!("Python modules by retained memory");
+    for (name, size) in modules.iter().take(TOP_MODULES) {
+        println!("  {:>12}  {name}", human(*size));
+    }
+    if modules.len() > TOP_MODULES {
+        let rest = &modules[TOP_MODULES..];
+        println!(
+            "  {:>12}  {} more modules",
+            human(rest.iter().map(|(_, size)| size).sum()),
+            rest.len()
+        );
+    }
+    Ok(())
+}
@@ -0,0 +1,659 @@
+use anyhow::{Context, Error};
+use std::path::{Component, Path, PathBuf};
+
+#[derive(Debug, PartialEq)]
+struct Requirement {
+    name: String,
+    version: Option<String>,
+}
+
+// Wheel filenames and requirements may spell the same project differently,
+// e.g. `Typing-Extensions` and `typing_extensions`
+fn normalize(name: &str) -> String {
+    let mut out = String::new();
+    for c in name.chars() {
+        if c == '-' || c == '_' || c == '.' {
+            if !out.ends_with('_') {
+                out.push('_');
+            }
+        } else {
+            out.push(c.to_ascii_lowercase());
+        }
+    }
+    out
+}
+
+/// The values of the environment markers (PEP 508) inside a plugin
+struct Environment {
+    python_version: String,
+    python_full_version: String,
+}
+
+impl Environment {
+    fn new(python_full_version: &str) -> Self {
+        let python_version = python_full_version
+            .split('.')
+            .take(2)
+            .collect::<Vec<_>>()
+            .join(".");
+        Environment {
+            python_version,
+            python_full_version: python_full_version.to_string(),
+        }
+    }
+
+    fn get(&self, var: &str) -> Option<String> {
+        let value = match var {
+            "os_name" => "posix",
+            "sys_platform" => "wasi",
+            "platform_system" => "wasi",
+            "platform_machine" => "wasm32",
+            "platform_release" | "platform_version" | "extra" => "",
+            "implementation_name" => "cpython",
+            "platform_python_implementation" => "CPython",
+            "python_version" => &self.python_version,
+            "python_full_version" | "implementation_version" => &self.python_full_version,
+            _ => return None,
+        };
+        Some(value.to_string())
+    }
+
+    /// Evaluate a marker expression like `sys_platform != "win32" and python_version >= "3.8"`
+    fn evaluate(&self, marker: &str) -> Result<bool, Error> {
+        let tokens =
+            tokenize(marker).with_context(|| format!("Invalid environment marker `{marker}`"))?;
+        let mut tokens = tokens.iter().peekable();
+        let result = self
+            .evaluate_or(&mut tokens)
+            .with_context(|| format!("Invalid environment marker `{marker}`"))?;
+        if tokens.next().is_some() {
+            anyhow::bail!("Invalid environment marker `{marker}`");
+        }
+        Ok(result)
+    }
+
+    fn evaluate_or(&self, tokens: &mut Tokens) -> Result<bool, Error> {
+        let mut result = self.evaluate_and(tokens)?;
+        while tokens
+            .next_if(|x| **x == Token::Word("or".into()))
+            .is_some()
+        {
+            result |= self.evaluate_and(tokens)?;
+        }
+        Ok(result)
+    }
+
+    fn evaluate_and(&self, tokens: &mut Tokens) -> Result<bool, Error> {
+        let mut result = self.evaluate_atom(tokens)?;
+        while tokens
+            .next_if(|x| **x == Token::Word("and".into()))
+            .is_some()
+        {
+            result &= self.evaluate_atom(tokens)?;
+        }
+        Ok(result)
+    }
+
+    fn evaluate_atom(&self, tokens: &mut Tokens) -> Result<bool, Error> {
+        if tokens.next_if(|x| **x == Token::Open).is_some() {
+            let result = self.evaluate_or(tokens)?;
+            if tokens.next() != Some(&Token::Close) {
+                anyhow::bail!("Unbalanced parentheses");
+            }
+            return Ok(result);
+        }
+
+        let lhs = self.value(tokens.next())?;
+        let op = match tokens.next() {
+            Some(Token::Op(op)) => op.clone(),
+            Some(Token::Word(word)) if word == "in" => word.clone(),
+            Some(Token::Word(word)) if word == "not" => {
+                if tokens.next() != Some(&Token::Word("in".into())) {
+                    anyhow::bail!("Expected `in` after `not`");
+                }
+                "not in".to_string()
+            }
+            _ => anyhow::bail!("Expected a comparison operator"),
+        };
+        let rhs = self.value(tokens.next())?;
+        Ok(compare(&lhs, &op, &rhs))
+    }
+
+    fn value(&self, token: Option<&Token>) -> Result<String, Error> {
+        match token {
+            Some(Token::Str(value)) => Ok(value.clone()),
+            Some(Token::Word(var)) => match self.get(var) {
+                Some(value) => Ok(value),
+                None => anyhow::bail!("Unknown marker variable `{var}`"),
+            },
+            _ => anyhow::bail!("Expected a marker variable or a quoted string"),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum Token {
+    Str(String),
+    Word(String),
+    Op(String),
+    Open,
+    Close,
+}
+
+type Tokens<'a> = std::iter::Peekable<std::slice::Iter<'a, Token>>;
+
+fn tokenize(marker: &str) -> Result<Vec<Token>, Error> {
+    let mut tokens = vec![];
+    let mut rest = marker.trim_start();
+    while let Some(c) = rest.chars().next() {
+        let len = if c == '(' || c == ')' {
+            tokens.push(if c == '(' { Token::Open } else { Token::Close });
+            1
+        } else if c == '"' || c == '\'' {
+            let Some(end) = rest[1..].find(c) else {
+                anyhow::bail!("Unterminated string");
+            };
+            tokens.push(Token::Str(rest[1..end + 1].to_string()));
+            end + 2
+        } else if let Some(op) = ["===", "==", "!=", "<=", ">=", "~=", "<", ">"]
+            .iter()
+            .find(|op| rest.starts_with(**op))
+        {
+            tokens.push(Token::Op(op.to_string()));
+            op.len()
+        } else if c.is_ascii_alphabetic() || c == '_' {
+            let len = rest
+                .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_' || c == '.'))
+                .unwrap_or(rest.len());
+            tokens.push(Token::Word(rest[..len].to_string()));
+            len
+        } else {
+            anyhow::bail!("Unexpected character `{c}`");
+        };
+        rest = rest[len..].trim_start();
+    }
+    Ok(tokens)
+}
+
+/// The release segment of a version, `None` when it isn't a version
+fn release(version: &str) -> Option<Vec<u64>> {
+    version
+        .split('.')
+        .map(|part| {
+            let digits = part
+                .find(|c: char| !c.is_ascii_digit())
+                .unwrap_or(part.len());
+            part[..digits].parse().ok()
+        })
+        .collect()
+}
+
+fn compare_release(a: &[u64], b: &[u64]) -> std::cmp::Ordering {
+    let len = a.len().max(b.len());
+    let pad = |v: &[u64]| {
+        let mut v = v.to_vec();
+        v.resize(len, 0);
+        v
+    };
+    pad(a).cmp(&pad(b))
+}
+
+fn compare(lhs: &str, op: &str, rhs: &str) -> bool {
+    use std::cmp::Ordering::*;
+
+    match op {
+        "in" => return rhs.contains(lhs),
+        "not in" => return !rhs.contains(lhs),
+        "===" => return lhs == rhs,
+        _ => (),
+    }
+
+    // `== "3.*"` and `!= "3.*"` match on a prefix of the release
+    if let Some(prefix) = rhs.strip_suffix(".*") {
+        if let (Some(version), Some(prefix)) = (release(lhs), release(prefix)) {
+            let matches = version.len() >= prefix.len() && version[..prefix.len()] == prefix[..];
+            return match op {
+                "==" => matches,
+                "!=" => !matches,
+                _ => false,
+            };
+        }
+    }
+
+    let (Some(a), Some(b)) = (release(lhs), release(rhs)) else {
+        return match op {
+            "==" => lhs == rhs,
+            "!=" => lhs != rhs,
+            _ => false,
+        };
+    };
+    let ordering = compare_release(&a, &b);
+    match op {
+        "==" => ordering == Equal,
+        "!=" => ordering != Equal,
+        "<" => ordering == Less,
+        "<=" => ordering != Greater,
+        ">" => ordering == Greater,
+        ">=" => ordering != Less,
+        // `~= 2.2` means `>= 2.2, == 2.*`
+        "~=" => {
+            let prefix = &b[..b.len().saturating_sub(1).max(1)];
+            ordering != Less && a.len() >= prefix.len() && a[..prefix.len()] == prefix[..]
+        }
+        _ => false,
+    }
+}
+
+/// Parse a requirement line, `None` when its environment marker doesn't match the plugin
+fn parse_requirement(line: &str, env: &Environment) -> Result<Option<Requirement>, Error> {
+    // Hashes and extras are dropped, only the name and pinned version matter
+    let (line, marker) = line.split_once(';').unwrap_or((line, ""));
+    let marker = marker.split(" --").next().unwrap_or_default().trim();
+    if !marker.is_empty() && !env.evaluate(marker)? {
+        return Ok(None);
+    }
+
+    let url = line.contains('@');
+    let line = line.split_whitespace().next().unwrap_or_default();
+    let (name, version) = match line.split_once("===").or_else(|| line.split_once("==")) {
+        Some((name, version)) => (name, Some(version.to_string())),
+        None => (line, None),
+    };
+    let name = name.split('[').next().unwrap_or_default();
+    if url || name.contains(['<', '>', '=', '!', '~', '/']) {
+        anyhow::bail!(
+            "Unsupported requirement `{line}`, requirements must be pinned with `==` since \
+             dependencies are not resolved"
+        );
+    }
+    Ok(Some(Requirement {
+        name: name.to_string(),
+        version,
+    }))
+}
+
+fn parse_requirements_txt(data: &str, env: &Environment) -> Result<Vec<Requirement>, Error> {
+    let data = data.replace("\\\n", " ");
+    let mut requirements = vec![];
+    for line in data.lines() {
+        let line = line.split('#').next().unwrap_or_default().trim();
+        if line.is_empty() || line.starts_with('-') {
+            continue;
+        }
+        requirements.extend(parse_requirement(line, env)?);
+    }
+    Ok(requirements)
+}
+
+/// The packages the project in `uv.lock` depends on inside a plugin, following the
+/// dependencies whose marker matches and skipping the development groups
+fn parse_uv_lock(data: &str, env: &Environment) -> Result<Vec<Requirement>, Error> {
+    let lock: toml::Table = data.parse()?;
+    let packages = lock
+        .get("package")
+        .and_then(|x| x.as_array())
+        .cloned()
+        .unwrap_or_default();
+    let packages: Vec<&toml::Table> = packages.iter().filter_map(|x| x.as_table()).collect();
+
+    let name_of = |package: &toml::Table| -> Result<String, Error> {
+        match package.get("name").and_then(|x| x.as_str()) {
+            Some(name) => Ok(name.to_string()),
+            None => anyhow::bail!("Invalid uv.lock, package is missing a name"),
+        }
+    };
+    let version_of = |package: &toml::Table| {
+        package
+            .get("version")
+            .and_then(|x| x.as_str())
+            .map(String::from)
+    };
+
+    // The project itself is part of the lock file but isn't a dependency
+    let mut pending: Vec<(usize, Option<String>)> = vec![];
+    for (index, package) in packages.iter().enumerate() {
+        let source = package.get("source").and_then(|x| x.as_table());
+        if source.is_some_and(|x| x.contains_key("editable") || x.contains_key("virtual")) {
+            pending.push((index, None));
+        }
+    }
+    if pending.is_empty() {
+        anyhow::bail!("Invalid uv.lock, no editable or virtual project found");
+    }
+    let projects: Vec<usize> = pending.iter().map(|(index, _)| *index).collect();
+
+    let mut seen = std::collections::HashSet::new();
+    let mut requirements = vec![];
+    while let Some((index, extra)) = pending.pop() {
+        if !seen.insert((index, extra.clone())) {
+            continue;
+        }
+        let package = packages[index];
+        if extra.is_none() && !projects.contains(&index) {
+            requirements.push(Requirement {
+                name: name_of(package)?,
+                version: version_of(package),
+            });
+        }
+
+        let dependencies = match &extra {
+            None => package.get("dependencies"),
+            Some(extra) => package
+                .get("optional-dependencies")
+                .and_then(|x| x.get(extra.as_str())),
+        };
+        for dependency in dependencies
+            .and_then(|x| x.as_array())
+            .into_iter()
+            .flatten()
+        {
+            let marker = dependency.get("marker").and_then(|x| x.as_str());
+            if marker.is_some() && !env.evaluate(marker.unwrap_or_default())? {
+                continue;
+            }
+            let Some(name) = dependency.get("name").and_then(|x| x.as_str()) else {
+                anyhow::bail!("Invalid uv.lock, dependency is missing a name");
+            };
+            let version = dependency.get("version").and_then(|x| x.as_str());
+            let Some(dep_index) = packages.iter().position(|package| {
+                package.get("name").and_then(|x| x.as_str()) == Some(name)
+                    && version.map_or(true, |v| version_of(package).as_deref() == Some(v))
+            }) else {
+                anyhow::bail!("Invalid uv.lock, {name} is a dependency but isn't locked");
+            };
+
+            pending.push((dep_index, None));
+            let extras = dependency.get("extra").and_then(|x| x.as_array());
+            for extra in extras.into_iter().flatten().filter_map(|x| x.as_str()) {
+                pending.push((dep_index, Some(extra.to_string())));
+            }
+        }
+    }
+    Ok(requirements)
+}
+
+fn find_wheel(wheelhouse: &Path, requirement: &Requirement) -> Result<PathBuf, Error> {
+    let mut native = None;
+    let mut compatible = vec![];
+    for entry in std::fs::read_dir(wheelhouse)? {
+        let path = entry?.path();
+        if path.extension().and_then(|x| x.to_str()) != Some("whl") {
+            continue;
+        }
+
+        // {name}-{version}(-{build})?-{python}-{abi}-{platform}.whl
+        let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+        let parts: Vec<&str> = stem.split('-').collect();
+        if parts.len() < 5
+            || normalize(parts[0]) != normalize(&requirement.name)
+            || requirement
+                .version
+                .as_deref()
+                .is_some_and(|v| v != parts[1])
+        {
+            continue;
+        }
+
+        // Wheels built for other platforms can't be loaded by the plugin, and the
+        // ones built for wasm32-wasi are rejected when they contain extension modules
+        let platform = parts[parts.len() - 1];
+        if platform == "any" || platform.split('.').any(|x| x.contains("wasi")) {
+            compatible.push(path);
+        } else {
+            native = Some(path);
+        }
+    }
+
+    match (compatible.len(), native) {
+        (1, _) => Ok(compatible.remove(0)),
+        (0, Some(native)) => anyhow::bail!(
+            "{} is built for another platform, only pure Python and wasm32-wasi wheels are supported",
+            native.display()
+        ),
+        (0, None) => anyhow::bail!(
+            "No wheel found for {}{} in {}",
+            requirement.name,
+            requirement
+                .version
+                .as_ref()
+                .map(|v| format!("=={v}"))
+                .unwrap_or_default(),
+            wheelhouse.display()
+        ),
+        _ => anyhow::bail!(
+            "Multiple wheels found for {} in {}, pin a version with `==`",
+            requirement.name,
+            wheelhouse.display()
+        ),
+    }
+}
+
+fn unpack_wheel(wheel: &Path, dest: &Path) -> Result<(), Error> {
+    let file = std::fs::File::open(wheel)?;
+    let mut archive = zip::ZipArchive::new(file)?;
+    for i in 0..archive.len() {
+        let mut entry = archive.by_index(i)?;
+        let Some(path) = entry.enclosed_name() else {
+            anyhow::bail!("Invalid path in {}: {}", wheel.display(), entry.name());
+        };
+        if entry.is_dir() {
+            continue;
+        }
+
+        let extension = path
+            .extension()
+            .and_then(|x| x.to_str())
+            .unwrap_or_default();
+        if ["so", "pyd", "dylib"].contains(&extension) {
+            anyhow::bail!(
+                "{} contains native extension module {}, only pure Python wheels are supported",
+                wheel.display(),
+                path.display()
+            );
+        }
+
+        // Files in `{name}.data/purelib` are installed next to the packages, other
+        // data directories (scripts, headers) aren't needed by the plugin
+        let mut components = path.components();
+        let first = components.next();
+        let path = match first {
+            Some(Component::Normal(dir)) if dir.to_string_lossy().ends_with(".data") => {
+                match components.next() {
+                    Some(Component::Normal(kind)) if kind == "purelib" || kind == "platlib" => {
+                        components.as_path().to_path_buf()
+                    }
+                    _ => continue,
+                }
+            }
+            _ => path,
+        };
+
+        let out = dest.join(path);
+        if let Some(parent) = out.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let mut out = std::fs::File::create(out)?;
+        std::io::copy(&mut entry, &mut out)?;
+    }
+    Ok(())
+}
+
+/// Unpack the wheels listed in `requirements` (a requirements.txt or uv.lock file) from
+/// `wheelhouse` into `dest`, without touching the network. Environment markers are
+/// evaluated for the plugin, a wasm32-wasi CPython `python_full_version`
+pub(crate) fn vendor(
+    requirements: &Path,
+    wheelhouse: &Path,
+    dest: &Path,
+    python_full_version: &str,
+) -> Result<(), Error> {
+    let data = std::fs::read_to_string(requirements)
+        .with_context(|| format!("Unable to read {}", requirements.display()))?;
+    let env = Environment::new(python_full_version);
+    let requirements = if requirements.extension().and_then(|x| x.to_str()) == Some("lock") {
+        parse_uv_lock(&data, &env)?
+    } else {
+        parse_requirements_txt(&data, &env)?
+    };
+
+    std::fs::create_dir_all(dest)?;
+    for requirement in requirements {
+        let wheel = find_wheel(wheelhouse, &requirement)?;
+        log::info!("Vendoring {}", wheel.display());
+        unpack_wheel(&wheel, dest)
+            .with_context(|| format!("Unable to unpack {}", wheel.display()))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn env() -> Environment {
+        Environment::new("3.12.4")
+    }
+
+    fn requirement(name: &str, version: &str) -> Requirement {
+        Requirement {
+            name: name.to_string(),
+            version: Some(version.to_string()),
+        }
+    }
+
+    #[test]
+    fn markers() {
+        let env = env();
+        assert!(env.evaluate("sys_platform == 'wasi'").unwrap());
+        assert!(!env
+            .evaluate("sys_platform == \"win32\" or os_name == 'nt'")
+            .unwrap());
+        assert!(env
+            .evaluate("python_version >= '3.8' and python_version < '3.13'")
+            .unwrap());
+        assert!(!env.evaluate("python_version < '3.10'").unwrap());
+        assert!(env.evaluate("python_full_version >= '3.12.3'").unwrap());
+        assert!(!env.evaluate("python_full_version < '3.12.4'").unwrap());
+        assert!(env.evaluate("python_version == '3.12'").unwrap());
+        assert!(env.evaluate("python_version == '3.*'").unwrap());
+        assert!(env.evaluate("python_version ~= '3.10'").unwrap());
+        assert!(!env.evaluate("python_version ~= '3.10.1'").unwrap());
+        assert!(env.evaluate("'wasm' in platform_machine").unwrap());
+        assert!(env
+            .evaluate("(os_name == 'nt' or sys_platform == 'wasi') and extra == ''")
+            .unwrap());
+        assert!(env
+            .evaluate("platform_system not in 'Windows Darwin'")
+            .unwrap());
+        assert!(env.evaluate("unknown == '1'").is_err());
+        assert!(env.evaluate("(os_name == 'posix'").is_err());
+    }
+
+    #[test]
+    fn requirements_txt() {
+        let data = "\
+# comment
+requests==2.31.0 \\
+    --hash=sha256:abc
+typing-extensions===4.12.2
+colorama==0.4.6 ; sys_platform == 'win32'
+tomli==2.0.1 ; python_version < \"3.11\" --hash=sha256:def
+idna[all]==3.7  # inline comment
+-e .
+";
+        assert_eq!(
+            parse_requirements_txt(data, &env()).unwrap(),
+            vec![
+                requirement("requests", "2.31.0"),
+                requirement("typing-extensions", "4.12.2"),
+                requirement("idna", "3.7"),
+            ]
+        );
+        assert!(parse_requirements_txt("requests>=2", &env()).is_err());
+        assert!(parse_requirements_txt("pkg @ https://example.com/pkg.whl", &env()).is_err());
+    }
+
+    #[test]
+    fn uv_lock() {
+        let data = r#"
+version = 1
+
+[[package]]
+name = "plugin"
+version = "0.1.0"
+source = { editable = "." }
+dependencies = [
+    { name = "requests" },
+    { name = "colorama", marker = "sys_platform == 'win32'" },
+    { name = "tomli", marker = "python_full_version < '3.11'" },
+]
+
+[package.dev-dependencies]
+dev = [{ name = "pytest" }]
+
+[[package]]
+name = "requests"
+version = "2.31.0"
+source = { registry = "https://pypi.org/simple" }
+dependencies = [{ name = "idna", extra = ["codec"] }]
+
+[[package]]
+name = "idna"
+version = "3.7"
+source = { registry = "https://pypi.org/simple" }
+
+[package.optional-dependencies]
+codec = [{ name = "codec" }]
+
+[[package]]
+name = "codec"
+version = "1.0"
+source = { registry = "https://pypi.org/simple" }
+
+[[package]]
+name = "colorama"
+version = "0.4.6"
+source = { registry = "https://pypi.org/simple" }
+
+[[package]]
+name = "tomli"
+version = "2.0.1"
+source = { registry = "https://pypi.org/simple" }
+
+[[package]]
+name = "pytest"
+version = "8.0.0"
+source = { registry = "https://pypi.org/simple" }
+"#;
+        let mut requirements = parse_uv_lock(data, &env()).unwrap();
+        requirements.sort_by(|a, b| a.name.cmp(&b.name));
+        assert_eq!(
+            requirements,
+            vec![
+                requirement("codec", "1.0"),
+                requirement("idna", "3.7"),
+                requirement("requests", "2.31.0"),
+            ]
+        );
+    }
+
+    #[test]
+    fn wheel_platforms() {
+        let dir = tempfile::TempDir::new().unwrap();
+        for name in [
+            "six-1.16.0-py2.py3-none-any.whl",
+            "native-1.0-cp312-cp312-manylinux_2_17_x86_64.whl",
+            "wasi-1.0-cp312-cp312-wasi_0_0_0_wasm32.whl",
+            "wasi-1.0-cp312-cp312-win_amd64.whl",
+        ] {
+            std::fs::write(dir.path().join(name), "").unwrap();
+        }
+
+        let wheel = find_wheel(dir.path(), &requirement("Six", "1.16.0")).unwrap();
+        assert!(wheel.ends_with("six-1.16.0-py2.py3-none-any.whl"));
+        let wheel = find_wheel(dir.path(), &requirement("wasi", "1.0")).unwrap();
+        assert!(wheel.ends_with("wasi-1.0-cp312-cp312-wasi_0_0_0_wasm32.whl"));
+        assert!(find_wheel(dir.path(), &requirement("native", "1.0")).is_err());
+        assert!(find_wheel(dir.path(), &requirement("six", "1.15.0")).is_err());
+    }
+}
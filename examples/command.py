@@ -0,0 +1,7 @@
+import os
+import sys
+
+
+def main():
+    name = os.environ.get("USER", "world")
+    print(f"Hello, {name}! Arguments: {sys.argv[1:]}")
@@ -0,0 +1,16 @@
+import re
+import extism
+
+PATTERNS = []
+
+
+@extism.on_init
+def setup():
+    for word in extism.Config.get_str("words").split(","):
+        PATTERNS.append(re.compile(rf"\b{word}\b", re.IGNORECASE))
+
+
+@extism.plugin_fn
+def count_words():
+    input = extism.input_str()
+    extism.output({"count": sum(len(p.findall(input)) for p in PATTERNS)})
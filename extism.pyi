@@ -16,8 +16,43 @@ It includes functionality for handling plugin I/O, HTTP requests, memory managem
 configuration, and host function interactions.
 """
 
-from typing import Any, TypeVar, Callable, Optional, Union, Dict, List, Type, TypeAlias, overload
+from typing import Any, TypeVar, Callable, Optional, Union, Dict, List, Tuple, Type, TypeAlias, overload
 from enum import Enum
+from collections.abc import Mapping, MutableMapping
+import io
+
+__all__ = [
+    "LogLevel",
+    "input_str",
+    "input_length",
+    "input_bytes",
+    "output_str",
+    "output_bytes",
+    "memory",
+    "log",
+    "HttpRequest",
+    "Error",
+    "set_error",
+    "LimitExceeded",
+    "import_fn",
+    "plugin_fn",
+    "shared_fn",
+    "on_init",
+    "before_call",
+    "after_call",
+    "on_error",
+    "input_json",
+    "output_json",
+    "input",
+    "InputReader",
+    "output",
+    "Var",
+    "Config",
+    "config",
+    "Headers",
+    "HttpResponse",
+    "Http",
+]
 
 class LogLevel(Enum):
     Trace: LogLevel
@@ -38,6 +73,16 @@ class memory:
     @staticmethod
     def bytes(mem: MemoryHandle) -> bytes: ...
     
+    @staticmethod
+    def read_into(mem: MemoryHandle, buffer: Any, offset: int = 0) -> int:
+        """Copy a block into a writable buffer, returns the number of bytes copied"""
+        ...
+    
+    @staticmethod
+    def write(mem: MemoryHandle, data: Any, offset: int = 0) -> None:
+        """Copy a buffer into a block, starting at `offset`"""
+        ...
+    
     @staticmethod
     def string(mem: MemoryHandle) -> str: ...
     
@@ -45,7 +90,7 @@ class memory:
     def free(mem: MemoryHandle) -> None: ...
     
     @staticmethod
-    def alloc(data: bytes) -> MemoryHandle: ...
+    def alloc(data: Any) -> MemoryHandle: ...
 
 class HttpRequest:
     url: str
@@ -54,6 +99,11 @@ class HttpRequest:
     
     def __init__(self, url: str, method: Optional[str] = None, headers: Optional[Dict[str, str]] = None) -> None: ...
 
+class Headers(MutableMapping[str, str]):
+    """Case-insensitive HTTP headers. Extism passes headers to and from the host as a
+    map, so a name has a single value: repeated headers are joined with commas"""
+    def __init__(self, headers: Optional[Union[Mapping[str, str], List[Tuple[str, str]]]] = None) -> None: ...
+
 class _HttpResponseInternal:
     def status_code(self) -> int: ...
     def data(self) -> bytes: ...
@@ -81,8 +131,10 @@ class HttpResponse:
         """Get response body JSON"""
         ...
     
-    def headers(self) -> Dict[str, str]:
-        """Get HTTP response headers"""
+    def headers(self) -> Headers:
+        """Get HTTP response headers. The host passes one value per name, repeated
+        headers like `Set-Cookie` are joined or only one of them is kept, depending
+        on the host"""
         ...
 
 class Http:
@@ -91,19 +143,53 @@ class Http:
         url: str,
         meth: str = "GET",
         body: Optional[Union[bytes, str]] = None,
-        headers: Optional[Dict[str, str]] = None
+        headers: Optional[Union[Mapping[str, str], List[Tuple[str, str]]]] = None,
+        *,
+        json: Any = None,
+        params: Optional[Union[Mapping[str, Any], List[Tuple[str, Any]]]] = None,
+        auth: Optional[Union[Tuple[str, str], str]] = None,
     ) -> HttpResponse:
-        """Make an HTTP request"""
+        """Make an HTTP request
+
+        `headers` is a dict or a list of pairs, a name that is repeated is sent once
+        with the values joined by commas. `json` is encoded as the body, `params` is appended to the URL
+        as the query string and `auth` is a `(user, password)` tuple for basic or a
+        token for bearer authentication"""
         ...
 
 T = TypeVar('T')
 
 def log(level: LogLevel, msg: Union[str, bytes, Any]) -> None: ...
 
+class Error(Exception):
+    """An exception with an error code, included in JSON error payloads"""
+    code: Optional[Union[str, int]]
+    def __init__(self, message: str, code: Optional[Union[str, int]] = None) -> None: ...
+
+class LimitExceeded(BaseException):
+    """Raised when a call exceeds one of the limits set in the Extism config, the
+    code is the config key. Like `KeyboardInterrupt` it isn't caught by
+    `except Exception`"""
+    code: str
+
+def set_error(error: Union[str, Dict[str, Any]]) -> None:
+    """Set the error returned to the host, a dict is encoded as JSON"""
+    ...
+
 def input_bytes() -> bytes: ...
 def output_bytes(result: bytes) -> None: ...
 def input_str() -> str: ...
 def output_str(result: str) -> None: ...
+def input_length() -> int:
+    """Get the length of the input in bytes without reading it"""
+    ...
+
+class InputReader(io.RawIOBase):
+    """Read the input in chunks without copying all of it into memory, wrap it in
+    `io.TextIOWrapper` to read text"""
+    def readinto(self, buffer: Any) -> int: ...
+    def seek(self, offset: int, whence: int = ...) -> int: ...
+    def tell(self) -> int: ...
 
 def import_fn(module: str, name: str) -> Callable[[Callable[..., Any]], Callable[..., Any]]:
     """Annotate an import function"""
@@ -117,6 +203,26 @@ def shared_fn(f: Callable[..., Any]) -> Callable[..., Any]:
     """Annotate a an export that won't be called directly by Extism"""
     ...
 
+def on_init(func: Callable[[], Any]) -> Callable[[], Any]:
+    """Annotate a function that runs once while the plugin is built"""
+    ...
+
+def before_call(func: Callable[[str], Any]) -> Callable[[str], Any]:
+    """Annotate a function that's called with the export name before every call"""
+    ...
+
+def after_call(func: Callable[[str], Any]) -> Callable[[str], Any]:
+    """Annotate a function that's called with the export name after every call,
+    including calls that raised an exception"""
+    ...
+
+def on_error(
+    func: Callable[[str, BaseException], Any],
+) -> Callable[[str, BaseException], Any]:
+    """Annotate a function that's called with the export name and the exception
+    when a call raises"""
+    ...
+
 def input_json(t: Optional[Type[T]] = None) -> Union[T, Any]:
     """Get input as JSON"""
     ...
@@ -148,8 +254,36 @@ class Var:
         ...
     
     @staticmethod
-    def set(key: str, value: Union[bytes, str]) -> None:
-        """Set a variable with a string or bytes value"""
+    def get_int(key: str, default: Optional[int] = None) -> Optional[int]:
+        """Get variable as int, or `default` if it's not set"""
+        ...
+    
+    @staticmethod
+    def get_float(key: str, default: Optional[float] = None) -> Optional[float]:
+        """Get variable as float, or `default` if it's not set"""
+        ...
+    
+    @staticmethod
+    def get_bool(key: str, default: Optional[bool] = None) -> Optional[bool]:
+        """Get variable as bool, or `default` if it's not set"""
+        ...
+    
+    @staticmethod
+    def exists(key: str) -> bool:
+        """Check whether a variable is set. Extism has no call for this, the host
+        still copies the whole value into plugin memory, but it's freed right away
+        instead of being converted to a Python object"""
+        ...
+    
+    @staticmethod
+    def set(key: str, value: Union[bytes, str, bool, int, float, None]) -> None:
+        """Set a variable with a string, bytes, bool, int or float value, `None`
+        removes it"""
+        ...
+    
+    @staticmethod
+    def delete(key: str) -> None:
+        """Remove a variable"""
         ...
 
 class Config:
@@ -158,7 +292,57 @@ class Config:
         """Get a config value as string"""
         ...
     
+    @staticmethod
+    def get_bytes(key: str) -> Optional[bytes]:
+        """Get a config value as bytes"""
+        ...
+    
     @staticmethod
     def get_json(key: str) -> Optional[Any]:
         """Get a config value as JSON"""
-        ...
\ No newline at end of file
+        ...
+    
+    @staticmethod
+    def get_int(key: str, default: Optional[int] = None) -> Optional[int]:
+        """Get a config value as int, or `default` if it's not set"""
+        ...
+    
+    @staticmethod
+    def get_float(key: str, default: Optional[float] = None) -> Optional[float]:
+        """Get a config value as float, or `default` if it's not set"""
+        ...
+    
+    @staticmethod
+    def get_bool(key: str, default: Optional[bool] = None) -> Optional[bool]:
+        """Get a config value as bool, or `default` if it's not set"""
+        ...
+    
+    @staticmethod
+    def exists(key: str) -> bool:
+        """Check whether a config key is set. Extism has no call for this, the host
+        still copies the whole value into plugin memory, but it's freed right away
+        instead of being converted to a Python object"""
+        ...
+
+class _Vars(MutableMapping[str, bytes]):
+    """Variables as a mapping of names to bytes. Extism can't list the names, so it
+    can't be iterated, measured or converted to a dict, and it's always true"""
+    def __setitem__(self, key: str, value: Union[bytes, str, bool, int, float]) -> None: ...
+    def get_str(self, key: str) -> Optional[str]: ...
+    def get_json(self, key: str) -> Optional[Any]: ...
+    def get_int(self, key: str, default: Optional[int] = None) -> Optional[int]: ...
+    def get_float(self, key: str, default: Optional[float] = None) -> Optional[float]: ...
+    def get_bool(self, key: str, default: Optional[bool] = None) -> Optional[bool]: ...
+
+class _Config(Mapping[str, str]):
+    """Config as a mapping of keys to strings. Extism can't list the keys, so it
+    can't be iterated, measured or converted to a dict, and it's always true"""
+    def get_bytes(self, key: str) -> Optional[bytes]: ...
+    def get_json(self, key: str) -> Optional[Any]: ...
+    def get_int(self, key: str, default: Optional[int] = None) -> Optional[int]: ...
+    def get_float(self, key: str, default: Optional[float] = None) -> Optional[float]: ...
+    def get_bool(self, key: str, default: Optional[bool] = None) -> Optional[bool]: ...
+
+# Left out of `__all__`, `from extism import *` would shadow the builtin
+vars: _Vars
+config: _Config
@@ -1,5 +1,55 @@
+use std::path::{Path, PathBuf};
+
 fn main() {
     use wlr_libpy::bld_cfg::configure_static_libs;
     configure_static_libs().unwrap().emit_link_flags();
     println!("cargo::rerun-if-changed=src/prelude.py");
+    println!("cargo::rerun-if-changed=src/tree_shake.py");
+    println!("cargo::rerun-if-changed=src/precompile.py");
+    println!("cargo::rerun-if-changed=src/reproducible.py");
+    println!("cargo::rerun-if-changed=src/size_report.py");
+    println!("cargo::rerun-if-changed=src/http_compat.py");
+    stamp_deps_version();
+}
+
+// Record the full CPython version provided by the downloaded wasi-deps, in the deps and
+// in the core, extism-py compares both before it starts a build
+fn stamp_deps_version() {
+    let deps = PathBuf::from(std::env::var("CARGO_MANIFEST_DIR").unwrap())
+        .join("target/wasm32-wasi/wasi-deps");
+    let Ok(entries) = std::fs::read_dir(deps.join("usr/local/lib")) else {
+        println!("cargo::warning=wasi-deps not found, skipping python-version stamp");
+        return;
+    };
+
+    for entry in entries.flatten() {
+        let name = entry.file_name().to_string_lossy().to_string();
+        if !name.starts_with("python3.") {
+            continue;
+        }
+        let Some(version) = read_patchlevel(&deps, &name) else {
+            println!("cargo::warning=patchlevel.h for {name} not found in wasi-deps, skipping python-version stamp");
+            return;
+        };
+        std::fs::write(deps.join("python-version"), format!("{version}\n")).unwrap();
+        println!("cargo::rustc-env=EXTISM_PYTHON_VERSION={version}");
+        return;
+    }
+}
+
+// The full version, like `3.12.1`, from the `PY_VERSION` define in the CPython headers
+fn read_patchlevel(deps: &Path, name: &str) -> Option<String> {
+    ["usr/local/include", "include"].iter().find_map(|include| {
+        let path = deps.join(include).join(name).join("patchlevel.h");
+        let header = std::fs::read_to_string(path).ok()?;
+        header.lines().find_map(|line| {
+            let mut tokens = line.split_whitespace();
+            match (tokens.next(), tokens.next(), tokens.next()) {
+                (Some("#define"), Some("PY_VERSION"), Some(version)) => {
+                    Some(version.trim_matches('"').to_string())
+                }
+                _ => None,
+            }
+        })
+    })
 }
@@ -0,0 +1,153 @@
+use std::cell::RefCell;
+
+pub enum Arg {
+    Int(i64),
+    Float(f64),
+}
+
+/// Arguments passed to exports by the shim
+///
+/// The shim calls `__arg_start`, one `__arg_*` function per parameter and then one of
+/// the `__invoke*` functions. Every call gets its own frame, so an export called from a
+/// host function while another export is running doesn't see the outer arguments.
+/// Misuse is recorded and reported by the next invoke instead of trapping, a call
+/// made while no export is running starts from an empty stack.
+#[derive(Default)]
+pub struct ArgStack {
+    frames: Vec<Vec<Arg>>,
+    error: Option<&'static str>,
+    running: usize,
+}
+
+impl ArgStack {
+    pub fn start(&mut self) {
+        // Anything left over belongs to a call that never reached its invoke
+        if self.running == 0 {
+            self.frames.clear();
+            self.error = None;
+        }
+        self.frames.push(Vec::new());
+    }
+
+    pub fn push(&mut self, arg: Arg) {
+        match self.frames.last_mut() {
+            Some(frame) => frame.push(arg),
+            None => self.error = Some("argument passed without __arg_start"),
+        }
+    }
+
+    /// Take the arguments of the export being invoked, `end` must be called once it
+    /// returns
+    pub fn finish(&mut self) -> Result<Vec<Arg>, &'static str> {
+        let frame = self.frames.pop();
+        if let Some(error) = self.error.take() {
+            return Err(error);
+        }
+        let frame = frame.ok_or("export invoked without __arg_start")?;
+        self.running += 1;
+        Ok(frame)
+    }
+
+    pub fn end(&mut self) {
+        self.running = self.running.saturating_sub(1);
+    }
+}
+
+thread_local! {
+    static CALL_ARGS: RefCell<ArgStack> = RefCell::new(ArgStack::default());
+}
+
+/// Run `f` with the argument stack, it must not call back into Python
+pub fn with_args<T>(f: impl FnOnce(&mut ArgStack) -> T) -> T {
+    CALL_ARGS.with_borrow_mut(f)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ints(args: Vec<Arg>) -> Vec<i64> {
+        args.into_iter()
+            .map(|arg| match arg {
+                Arg::Int(x) => x,
+                Arg::Float(x) => x as i64,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn single_call() {
+        let mut stack = ArgStack::default();
+        stack.start();
+        stack.push(Arg::Int(1));
+        stack.push(Arg::Float(2.0));
+        assert_eq!(ints(stack.finish().unwrap()), [1, 2]);
+        stack.end();
+        assert!(stack.finish().is_err());
+    }
+
+    #[test]
+    fn nested_calls() {
+        let mut stack = ArgStack::default();
+        stack.start();
+        stack.push(Arg::Int(1));
+        assert_eq!(ints(stack.finish().unwrap()), [1]);
+
+        // Called from a host function while the first export is running
+        stack.start();
+        stack.push(Arg::Int(2));
+        stack.start();
+        stack.push(Arg::Int(3));
+        assert_eq!(ints(stack.finish().unwrap()), [3]);
+        stack.end();
+        assert_eq!(ints(stack.finish().unwrap()), [2]);
+        stack.end();
+        stack.end();
+    }
+
+    #[test]
+    fn invoke_without_start() {
+        let mut stack = ArgStack::default();
+        assert!(stack.finish().is_err());
+        stack.start();
+        assert!(stack.finish().unwrap().is_empty());
+    }
+
+    #[test]
+    fn argument_without_start() {
+        let mut stack = ArgStack::default();
+        stack.push(Arg::Int(1));
+        assert!(stack.finish().is_err());
+
+        stack.start();
+        stack.push(Arg::Int(2));
+        assert_eq!(ints(stack.finish().unwrap()), [2]);
+    }
+
+    #[test]
+    fn nested_argument_without_start() {
+        let mut stack = ArgStack::default();
+        stack.start();
+        assert!(stack.finish().is_ok());
+        stack.push(Arg::Int(1));
+        stack.start();
+        assert!(stack.finish().is_err());
+    }
+
+    #[test]
+    fn stale_arguments_are_dropped() {
+        let mut stack = ArgStack::default();
+        // A call that never reached its invoke
+        stack.start();
+        stack.push(Arg::Int(1));
+        stack.push(Arg::Int(2));
+        // A stray argument without a call
+        stack.push(Arg::Int(3));
+
+        stack.start();
+        stack.push(Arg::Int(4));
+        assert_eq!(ints(stack.finish().unwrap()), [4]);
+        stack.end();
+        assert!(stack.frames.is_empty());
+    }
+}
@@ -0,0 +1,253 @@
+import importlib.abc
+import io
+import sys
+import warnings
+
+import extism
+
+# Headers the host's HTTP client sets itself
+_SKIP_HEADERS = {"host", "connection", "content-length", "transfer-encoding"}
+
+
+def _body_bytes(body):
+    if body is None:
+        return None
+    if isinstance(body, str):
+        return body.encode("iso-8859-1")
+    if isinstance(body, (bytes, bytearray, memoryview)):
+        return bytes(body)
+    if hasattr(body, "read"):
+        return _body_bytes(body.read())
+    return b"".join(_body_bytes(chunk) for chunk in body)
+
+
+def _send(method, url, headers, body):
+    """Make a request with `extism.Http`, returns the status, headers and body"""
+    headers = [
+        (name, value) for name, value in headers if name.lower() not in _SKIP_HEADERS
+    ]
+    try:
+        res = extism.Http.request(url, method, _body_bytes(body), headers)
+    except Exception as exc:
+        raise ConnectionError(f"{method} {url} failed: {exc}") from exc
+    return res.status_code, res.headers(), res.data_bytes()
+
+
+class HTTPResponse(io.BufferedIOBase):
+    """A response read into memory, compatible with `http.client.HTTPResponse`"""
+
+    def __init__(self, method, url, status, headers, body):
+        import http.client
+
+        self.status = self.code = status
+        self.reason = http.client.responses.get(status, "")
+        self.version = 11
+        self.will_close = True
+        self.url = url
+        self.msg = self.headers = http.client.HTTPMessage()
+        for name, value in headers.items():
+            self.headers[name] = value
+        self.length = len(body)
+        self._fp = io.BytesIO(b"" if method == "HEAD" else body)
+
+    def readable(self):
+        return True
+
+    def read(self, amt=None):
+        return self._fp.read(-1 if amt is None else amt)
+
+    def read1(self, n=-1):
+        return self._fp.read1(n)
+
+    def readinto(self, b):
+        return self._fp.readinto(b)
+
+    def readline(self, limit=-1):
+        return self._fp.readline(limit)
+
+    def close(self):
+        super().close()
+        self._fp.close()
+
+    def isclosed(self):
+        return self._fp.closed
+
+    def getheader(self, name, default=None):
+        values = self.headers.get_all(name)
+        return ", ".join(values) if values else default
+
+    def getheaders(self):
+        return list(self.headers.items())
+
+    def info(self):
+        return self.headers
+
+    def geturl(self):
+        return self.url
+
+    def getcode(self):
+        return self.status
+
+
+class _Connection:
+    """Replaces the socket handling of `http.client.HTTPConnection`, the request is
+    made by the host once the response is requested"""
+
+    scheme = "http"
+    _pending = None
+    _response = None
+
+    def set_tunnel(self, host, port=None, headers=None):
+        raise NotImplementedError("Tunnels aren't supported by Extism HTTP")
+
+    def connect(self):
+        pass
+
+    def close(self):
+        self._pending = None
+        if self._response is not None:
+            self._response.close()
+            self._response = None
+
+    def putrequest(self, method, url, skip_host=False, skip_accept_encoding=False):
+        self._pending = (method, url, [], [])
+
+    def putheader(self, header, *values):
+        if self._pending is None:
+            raise ConnectionError("putheader() called before putrequest()")
+        if isinstance(header, bytes):
+            header = header.decode("iso-8859-1")
+        values = [
+            v.decode("iso-8859-1") if isinstance(v, bytes) else str(v) for v in values
+        ]
+        self._pending[2].append((header, ", ".join(values)))
+
+    def endheaders(self, message_body=None, *, encode_chunked=False):
+        if message_body is not None:
+            self.send(message_body)
+
+    def send(self, data):
+        if self._pending is None:
+            raise ConnectionError("send() called before putrequest()")
+        self._pending[3].append(_body_bytes(data))
+
+    def request(self, method, url, body=None, headers={}, *, encode_chunked=False):
+        self.putrequest(method, url)
+        for name, value in headers.items():
+            self.putheader(name, value)
+        self.endheaders(body)
+
+    def _url(self, path):
+        if "://" in path:
+            return path
+        host = f"[{self.host}]" if ":" in self.host else self.host
+        if self.port != self.default_port:
+            host += f":{self.port}"
+        return f"{self.scheme}://{host}{path}"
+
+    def getresponse(self):
+        if self._pending is None:
+            raise ConnectionError("getresponse() called before request()")
+        method, path, headers, body = self._pending
+        self._pending = None
+        url = self._url(path)
+        status, res_headers, data = _send(
+            method, url, headers, b"".join(body) if body else None
+        )
+        self._response = HTTPResponse(method, url, status, res_headers, data)
+        return self._response
+
+
+def _patch_http_client(module):
+    # Subclassed so the helpers `urllib.request` borrows from the original still work
+    class HTTPConnection(_Connection, module.HTTPConnection):
+        pass
+
+    # `http.client` only defines it when `ssl` is available
+    class HTTPSConnection(HTTPConnection):
+        default_port = module.HTTPS_PORT
+        scheme = "https"
+
+        def __init__(self, host, port=None, *, context=None, **kwargs):
+            # The host makes the TLS connection with its own settings
+            if context is not None:
+                warnings.warn(
+                    "The SSL context passed to HTTPSConnection is ignored, Extism HTTP "
+                    "requests are verified by the host",
+                    RuntimeWarning,
+                    stacklevel=2,
+                )
+            super().__init__(host, port, **kwargs)
+
+    module.HTTPConnection = HTTPConnection
+    module.HTTPSConnection = HTTPSConnection
+
+
+def _patch_urllib_request(module):
+    class ExtismHandler(module.BaseHandler):
+        """Opens http and https URLs with Extism HTTP"""
+
+        # Ahead of the default handlers, which need sockets
+        handler_order = 100
+
+        def http_open(self, req):
+            method, url = req.get_method(), req.full_url
+            try:
+                status, headers, data = _send(method, url, req.header_items(), req.data)
+            except OSError as exc:
+                raise module.URLError(exc) from exc
+            res = HTTPResponse(method, url, status, headers, data)
+            res.msg = res.reason
+            return res
+
+        https_open = http_open
+
+    module.ExtismHandler = ExtismHandler
+    module.install_opener(module.build_opener(ExtismHandler))
+
+
+_PATCHES = {
+    "http.client": _patch_http_client,
+    "urllib.request": _patch_urllib_request,
+}
+
+
+class _PatchingLoader(importlib.abc.Loader):
+    def __init__(self, loader, patch):
+        self._loader = loader
+        self._patch = patch
+
+    def create_module(self, spec):
+        return self._loader.create_module(spec)
+
+    def exec_module(self, module):
+        self._loader.exec_module(module)
+        self._patch(module)
+
+    def __getattr__(self, name):
+        return getattr(self._loader, name)
+
+
+class _PatchingFinder(importlib.abc.MetaPathFinder):
+    """Patch the networking modules right after they are imported"""
+
+    def find_spec(self, name, path=None, target=None):
+        patch = _PATCHES.get(name)
+        if patch is None:
+            return None
+        for finder in sys.meta_path:
+            if finder is self or not hasattr(finder, "find_spec"):
+                continue
+            spec = finder.find_spec(name, path, target)
+            if spec is not None:
+                spec.loader = _PatchingLoader(spec.loader, patch)
+                return spec
+        return None
+
+
+def install():
+    """Route `urllib.request` and `http.client` through Extism HTTP"""
+    for name, patch in _PATCHES.items():
+        if name in sys.modules:
+            patch(sys.modules[name])
+    sys.meta_path.insert(0, _PatchingFinder())
@@ -1,12 +1,31 @@
-use pyo3::types::{PyModule, PyTuple, PyTracebackMethods};
+use pyo3::exceptions::PyException;
+use pyo3::types::{PyList, PyModule, PyTuple, PyTracebackMethods};
 use pyo3::{append_to_inittab, conversion::ToPyObject, prelude::*, Py, PyAny, PyResult, Python};
 
+mod args;
+use args::{with_args, Arg};
+
 mod py_module;
 use py_module::make_extism_ffi_module;
 
-use std::io::Read;
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Instant;
 
 const PRELUDE: &str = include_str!("prelude.py");
+const TREE_SHAKE: &str = include_str!("tree_shake.py");
+const PRECOMPILE: &str = include_str!("precompile.py");
+const REPRODUCIBLE: &str = include_str!("reproducible.py");
+const SIZE_REPORT: &str = include_str!("size_report.py");
+const HTTP_COMPAT: &str = include_str!("http_compat.py");
+
+// Set during initialization when the plugin is built with `--trace-calls`
+static TRACE_CALLS: AtomicBool = AtomicBool::new(false);
+
+// Set during initialization when the plugin is built with an `--error-detail` that
+// leaves out the traceback
+static REDACT_TRACEBACKS: AtomicBool = AtomicBool::new(false);
 
 fn convert_arg(py: Python, arg: Arg) -> PyObject {
     match arg {
@@ -15,24 +34,40 @@ fn convert_arg(py: Python, arg: Arg) -> PyObject {
     }
 }
 
-fn wrap_gil<T, F: FnOnce(Python) -> PyResult<T>>(err: T, f: F) -> T {
-    let result = Python::with_gil(|py| {
-        f(py).map_err(|err| {
-            let tb = err.traceback_bound(py).and_then(|x|{
-                if let Ok(x) = x.format() {
-                    Some(x)
-                } else {
-                    None
-                }
-            });
-            let mut s = err.into_value(py).to_string();
-            if let Some(tb) = tb {
-                s += "\n";
-                s += &tb;
-            }
-            s
+// Errors are formatted by the prelude so they look the same regardless of where they
+// were raised, the exception and traceback are used as-is if that fails
+fn format_error(py: Python, err: PyErr) -> String {
+    let formatted = PyModule::import_bound(py, "extism")
+        .and_then(|m| {
+            m.getattr("_format_error")?
+                .call1((err.value_bound(py).clone(),))
         })
+        .and_then(|x| x.extract::<String>());
+    if let Ok(formatted) = formatted {
+        return formatted;
+    }
+
+    if REDACT_TRACEBACKS.load(Ordering::Relaxed) {
+        return err.into_value(py).to_string();
+    }
+
+    let tb = err.traceback_bound(py).and_then(|x| {
+        if let Ok(x) = x.format() {
+            Some(x)
+        } else {
+            None
+        }
     });
+    let mut s = err.into_value(py).to_string();
+    if let Some(tb) = tb {
+        s += "\n";
+        s += &tb;
+    }
+    s
+}
+
+fn wrap_gil<T, F: FnOnce(Python) -> PyResult<T>>(err: T, f: F) -> T {
+    let result = Python::with_gil(|py| f(py).map_err(|err| format_error(py, err)));
     match result {
         Ok(x) => x,
         Err(error) => {
@@ -46,21 +81,72 @@ fn wrap_gil<T, F: FnOnce(Python) -> PyResult<T>>(err: T, f: F) -> T {
     }
 }
 
+fn invoke(py: Python, index: u32, shared: bool) -> PyResult<PyObject> {
+    let start = Instant::now();
+    let call_args = with_args(|args| args.finish())
+        .map_err(|err| PyException::new_err(format!("Invalid call into the Python core: {err}")))?;
+    let res = call_export(py, index, shared, call_args, start);
+    with_args(|args| args.end());
+    res
+}
+
+fn call_export(
+    py: Python,
+    index: u32,
+    shared: bool,
+    call_args: Vec<Arg>,
+    start: Instant,
+) -> PyResult<PyObject> {
+    let mut args: Vec<PyObject> = call_args.into_iter().map(|x| convert_arg(py, x)).collect();
+    let n_args = args.len();
+    args.insert(0, shared.to_object(py));
+    args.insert(0, index.to_object(py));
+    let args = PyTuple::new_bound(py, args);
+    let m = PyModule::import_bound(py, "extism_plugin")?;
+    let fun: Py<PyAny> = m.getattr("__invoke")?.into();
+
+    // Every call records its own host calls, an export called from another export
+    // gets a fresh list and the caller's list is restored afterwards
+    let trace = if TRACE_CALLS.load(Ordering::Relaxed) {
+        let extism = PyModule::import_bound(py, "extism")?;
+        let outer = extism.getattr("_trace_host_calls")?;
+        extism.setattr("_trace_host_calls", PyList::empty_bound(py))?;
+        Some((extism, outer))
+    } else {
+        None
+    };
+
+    let dispatch = start.elapsed();
+    let res = fun.call1(py, args);
+    if let Some((extism, outer)) = trace {
+        let python = start.elapsed() - dispatch;
+        let emitted = m.getattr("__trace_emit").and_then(|emit| {
+            emit.call1((
+                index,
+                n_args,
+                dispatch.as_nanos() as u64,
+                python.as_nanos() as u64,
+                res.is_ok(),
+            ))
+        });
+        let restored = extism.setattr("_trace_host_calls", outer);
+
+        // Tracing must never replace the result or the exception of the call itself
+        if let Err(err) = emitted.and(restored) {
+            extism_pdk::log!(
+                extism_pdk::LogLevel::Warn,
+                "Failed to emit call trace: {}",
+                err
+            );
+        }
+    }
+    res
+}
+
 #[no_mangle]
 pub extern "C" fn __invoke(index: u32, shared: bool) {
     wrap_gil((), |py| {
-        let call_args = unsafe { CALL_ARGS.pop() };
-        let mut args: Vec<PyObject> = call_args
-           .unwrap()
-            .into_iter()
-            .map(|x| convert_arg(py, x))
-            .collect();
-        args.insert(0, shared.to_object(py));
-        args.insert(0, index.to_object(py));
-        let args = PyTuple::new_bound(py, args);
-        let m = PyModule::import_bound(py, "extism_plugin")?;
-        let fun: Py<PyAny> = m.getattr("__invoke")?.into();
-        fun.call1(py, args)?;
+        invoke(py, index, shared)?;
         Ok(())
     });
 }
@@ -68,18 +154,7 @@ pub extern "C" fn __invoke(index: u32, shared: bool) {
 #[no_mangle]
 pub extern "C" fn __invoke_i32(index: u32, shared: bool) -> i32 {
     wrap_gil(-1, |py| -> PyResult<i32> {
-        let call_args = unsafe { CALL_ARGS.pop() };
-        let mut args: Vec<PyObject> = call_args
-            .unwrap()
-            .into_iter()
-            .map(|x| convert_arg(py, x))
-            .collect();
-        args.insert(0, shared.to_object(py));
-        args.insert(0, index.to_object(py));
-        let args = PyTuple::new_bound(py, args);
-        let m = PyModule::import_bound(py, "extism_plugin")?;
-        let fun: Py<PyAny> = m.getattr("__invoke")?.into();
-        let res = fun.call1(py, args)?;
+        let res = invoke(py, index, shared)?;
         if let Ok(res) = res.extract(py) {
             return Ok(res);
         }
@@ -90,77 +165,291 @@ pub extern "C" fn __invoke_i32(index: u32, shared: bool) -> i32 {
 #[no_mangle]
 pub extern "C" fn __invoke_i64(index: u32, shared: bool) -> i64 {
     wrap_gil(-1, |py| -> PyResult<i64> {
-        let call_args = unsafe { CALL_ARGS.pop() };
-        let mut args: Vec<PyObject> = call_args
-            .unwrap()
-            .into_iter()
-            .map(|x| convert_arg(py, x))
-            .collect();
-        args.insert(0, shared.to_object(py));
-        args.insert(0, index.to_object(py));
-        let args = PyTuple::new_bound(py, args);
-        let m = PyModule::import_bound(py, "extism_plugin")?;
-        let fun: Py<PyAny> = m.getattr("__invoke")?.into();
-        let res = fun.call1(py, args)?;
+        let res = invoke(py, index, shared)?;
         if let Ok(res) = res.extract(py) {
             return Ok(res);
         }
         Ok(0)
-    }).into()
+    })
 }
 
-enum Arg {
-    Int(i64),
-    Float(f64),
+#[no_mangle]
+pub extern "C" fn __command() {
+    let code = Python::with_gil(|py| {
+        let run = || -> PyResult<i32> {
+            // Arguments and environment are read from WASI on every call, the values
+            // captured in the snapshot belong to the build
+            let argv: Vec<OsString> = std::env::args_os().collect();
+            let environ: HashMap<OsString, OsString> = std::env::vars_os().collect();
+            let m = PyModule::import_bound(py, "extism_plugin")?;
+            let fun: Py<PyAny> = m.getattr("__command")?.into();
+            fun.call1(py, (argv, environ))?.extract(py)
+        };
+        run().unwrap_or_else(|err| {
+            err.print(py);
+            1
+        })
+    });
+    if code != 0 {
+        std::process::exit(code);
+    }
 }
 
-static mut CALL_ARGS: Vec<Vec<Arg>> = vec![];
-
 #[no_mangle]
 pub extern "C" fn __arg_start() {
-    unsafe {
-        CALL_ARGS.push(vec![]);
-    }
+    with_args(|args| args.start());
 }
 
 #[no_mangle]
 pub extern "C" fn __arg_i32(arg: i32) {
-    unsafe {
-        CALL_ARGS.last_mut().unwrap().push(Arg::Int(arg as i64));
-    }
+    with_args(|args| args.push(Arg::Int(arg as i64)));
 }
 
 #[no_mangle]
 pub extern "C" fn __arg_i64(arg: i64) {
-    unsafe {
-        CALL_ARGS.last_mut().unwrap().push(Arg::Int(arg));
-    }
+    with_args(|args| args.push(Arg::Int(arg)));
 }
 
 #[no_mangle]
 pub extern "C" fn __arg_f32(arg: f32) {
-    unsafe {
-        CALL_ARGS.last_mut().unwrap().push(Arg::Float(arg as f64));
-    }
+    with_args(|args| args.push(Arg::Float(arg as f64)));
 }
 
 #[no_mangle]
 pub extern "C" fn __arg_f64(arg: f64) {
-    unsafe {
-        CALL_ARGS.last_mut().unwrap().push(Arg::Float(arg));
+    with_args(|args| args.push(Arg::Float(arg)));
+}
+
+// The CPython version linked into the core, extism-py reads it from this custom
+// section and compares it with the wasi-deps before initializing the core
+const PYTHON_VERSION: &str = match option_env!("EXTISM_PYTHON_VERSION") {
+    Some(version) => version,
+    None => "",
+};
+
+#[link_section = "extism-py-python-version"]
+#[used]
+static PYTHON_VERSION_SECTION: [u8; PYTHON_VERSION.len()] = {
+    let version = PYTHON_VERSION.as_bytes();
+    let mut section = [0; PYTHON_VERSION.len()];
+    let mut i = 0;
+    while i < version.len() {
+        section[i] = version[i];
+        i += 1;
+    }
+    section
+};
+
+// extism-py maps the settings of the plugin being built and its source to this
+// directory, the environment of the build machine isn't visible to the core
+const BUILD_DIR: &str = "/extism-py-build";
+
+/// Set the environment from `BUILD_DIR/env`, `NUL` separated `KEY=VALUE` pairs, and
+/// return the plugin source
+fn load_build() -> String {
+    use std::ffi::OsStr;
+    use std::os::wasi::ffi::OsStrExt;
+
+    let env =
+        std::fs::read(format!("{BUILD_DIR}/env")).expect("Unable to read the build environment");
+    for var in env.split(|b| *b == 0).filter(|x| !x.is_empty()) {
+        let eq = var.iter().position(|b| *b == b'=').unwrap_or(var.len());
+        let value = var.get(eq + 1..).unwrap_or_default();
+        std::env::set_var(OsStr::from_bytes(&var[..eq]), OsStr::from_bytes(value));
     }
+    std::fs::read_to_string(format!("{BUILD_DIR}/source.py"))
+        .expect("Unable to read the plugin source")
 }
 
 #[export_name = "wizer.initialize"]
 extern "C" fn init() {
+    // CPython reads PYTHONPATH and PYTHONHASHSEED while it's initialized
+    let code = load_build();
     append_to_inittab!(make_extism_ffi_module);
     pyo3::prepare_freethreaded_python();
-    let mut code = String::new();
-    std::io::stdin().read_to_string(&mut code).unwrap();
-    Python::with_gil(|py| -> PyResult<()> {
-        PyModule::from_code_bound(py, PRELUDE, "<source>", "extism")?;
-        PyModule::from_code_bound(py, &code, "<source>", "extism_plugin")?;
+    let result = Python::with_gil(|py| -> PyResult<()> {
+        let reproducible = if std::env::var("EXTISM_PYTHON_REPRODUCIBLE").is_ok() {
+            let epoch: u64 = std::env::var("SOURCE_DATE_EPOCH")
+                .ok()
+                .and_then(|x| x.parse().ok())
+                .unwrap_or_default();
+            let m = PyModule::from_code_bound(
+                py,
+                REPRODUCIBLE,
+                "<reproducible>",
+                "extism_reproducible",
+            )?;
+            m.getattr("install")?.call1((epoch,))?;
+            Some(m)
+        } else {
+            None
+        };
+
+        let tree_shake = if std::env::var("EXTISM_PYTHON_TREE_SHAKE").is_ok() {
+            let m = PyModule::from_code_bound(py, TREE_SHAKE, "<tree_shake>", "extism_tree_shake")?;
+            m.getattr("install")?.call0()?;
+            Some(m)
+        } else {
+            None
+        };
+
+        let extism = PyModule::from_code_bound(py, PRELUDE, "<prelude>", "extism")?;
+        if let Ok(format) = std::env::var("EXTISM_PYTHON_ERROR_FORMAT") {
+            extism.setattr("_error_format", format)?;
+        }
+        if let Ok(detail) = std::env::var("EXTISM_PYTHON_ERROR_DETAIL") {
+            REDACT_TRACEBACKS.store(detail != "traceback", Ordering::Relaxed);
+            extism.setattr("_error_detail", detail)?;
+        }
+        if std::env::var("EXTISM_PYTHON_TRACE_CALLS").is_ok() {
+            TRACE_CALLS.store(true, Ordering::Relaxed);
+            extism.setattr("_trace_host_calls", PyList::empty_bound(py))?;
+        }
+
+        // Compile the modules on PYTHONPATH that the plugin uses now, so imports made at
+        // call time don't have to read and compile sources on every fresh instance
+        let precompile = if std::env::var("EXTISM_PYTHON_PRECOMPILE").is_ok() {
+            let python_path = std::env::var("PYTHONPATH").unwrap_or_default();
+            let paths: Vec<&str> = python_path.split(':').filter(|p| !p.is_empty()).collect();
+            let m = PyModule::from_code_bound(py, PRECOMPILE, "<precompile>", "extism_precompile")?;
+            Some(m.getattr("install")?.call1((paths,))?)
+        } else {
+            None
+        };
+
+        // Send requests made with `urllib.request` and `http.client` through Extism HTTP,
+        // the modules are patched when they are imported
+        let http_compat =
+            PyModule::from_code_bound(py, HTTP_COMPAT, "<http_compat>", "extism_http_compat")?;
+        http_compat.getattr("install")?.call0()?;
+
+        // Modules passed to `--preload` are part of the snapshot even if the plugin only
+        // imports them inside functions
+        let preload = std::env::var("EXTISM_PYTHON_PRELOAD").unwrap_or_default();
+        let preload: Vec<&str> = preload.split(',').filter(|name| !name.is_empty()).collect();
+        for name in &preload {
+            PyModule::import_bound(py, *name)?;
+        }
+
+        let plugin = PyModule::from_code_bound(py, &code, "<source>", "extism_plugin")?;
+
+        // Build the dispatch table from the export names found by the compiler, the
+        // shim refers to exports by their position in this list
+        let exports = std::env::var("EXTISM_PYTHON_EXPORTS").map_err(|_| {
+            PyException::new_err(
+                "EXTISM_PYTHON_EXPORTS is not set, the core has to be initialized by extism-py",
+            )
+        })?;
+        let names: Vec<&str> = exports.split(',').filter(|name| !name.is_empty()).collect();
+        plugin.getattr("__verify_exports")?.call1((names,))?;
+
+        // Run the `@extism.on_init` functions so their results end up in the snapshot,
+        // host config isn't available yet so they get the config passed to the compiler
+        let build_config: HashMap<String, String> = std::env::var("EXTISM_PYTHON_BUILD_CONFIG")
+            .unwrap_or_default()
+            .lines()
+            .filter_map(|x| x.split_once('='))
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        extism.getattr("_run_init_hooks")?.call1((build_config,))?;
+
+        // Modules only imported inside functions are compiled once everything that runs
+        // during initialization has imported what it needs
+        if let Some(precompile) = precompile {
+            precompile.getattr("finish")?.call1((&plugin,))?;
+        }
+
+        if let Some(tree_shake) = tree_shake {
+            // The HTTP compat finder stays on `sys.meta_path`, so its module is kept
+            let mut roots = vec![
+                "extism",
+                "extism_ffi",
+                "extism_plugin",
+                "extism_http_compat",
+            ];
+            roots.extend(&preload);
+            tree_shake.getattr("shake")?.call1((roots,))?;
+        }
+
+        if let Some(reproducible) = reproducible {
+            reproducible.getattr("finish")?.call0()?;
+        }
+
+        // Installed last, output written during initialization goes to the build's stdio
+        if let (Ok(stdout), Ok(stderr)) = (
+            std::env::var("EXTISM_PYTHON_STDOUT_LEVEL"),
+            std::env::var("EXTISM_PYTHON_STDERR_LEVEL"),
+        ) {
+            extism.getattr("_install_stdio")?.call1((stdout, stderr))?;
+        }
+
+        // Measured last so the numbers match what ends up in the snapshot, the report
+        // module itself is removed again before the snapshot is taken
+        if let Ok(path) = std::env::var("EXTISM_PYTHON_SIZE_REPORT") {
+            let m =
+                PyModule::from_code_bound(py, SIZE_REPORT, "<size_report>", "extism_size_report")?;
+            m.getattr("report")?.call1((path,))?;
+            PyModule::import_bound(py, "sys")?
+                .getattr("modules")?
+                .del_item("extism_size_report")?;
+        }
         Ok(())
-    })
-    .expect("initialize python code")
+    });
+
+    // Fail the build with the Python traceback
+    if let Err(err) = result {
+        Python::with_gil(|py| err.print(py));
+        std::process::exit(1);
+    }
+
+    if std::env::var("EXTISM_PYTHON_TREE_SHAKE").is_ok() {
+        scrub_free_memory();
+    }
+}
+
+// Freed memory keeps its old contents, so the modules dropped by tree shaking would
+// still be part of the snapshot. The free memory is allocated again zeroed, until the
+// allocator has to grow the heap, and released, which only leaves its headers behind.
+// Blocks freed by pymalloc stay in their arena until the whole arena is released, so
+// only arenas that were emptied completely are cleared
+fn scrub_free_memory() {
+    use std::alloc::{alloc_zeroed, dealloc, Layout};
+    use std::arch::wasm32::memory_size;
+
+    // The blocks are linked through their first two words: the next block and the size
+    let mut head: *mut usize = std::ptr::null_mut();
+    let mut top: Option<*mut usize> = None;
+    let mut size = 1 << 20;
+    while size >= 64 {
+        let layout = Layout::from_size_align(size, 8).unwrap();
+        loop {
+            let pages = memory_size::<0>();
+            let block = unsafe { alloc_zeroed(layout) } as *mut usize;
+            if block.is_null() {
+                break;
+            }
+
+            // Everything after the block that grew the heap is fresh, zeroed memory
+            if top.is_some_and(|top| block >= top) {
+                unsafe { dealloc(block as *mut u8, layout) };
+                break;
+            }
+            unsafe {
+                *block = head as usize;
+                *block.add(1) = size;
+            }
+            head = block;
+            if memory_size::<0>() != pages {
+                top = Some(block);
+                break;
+            }
+        }
+        size /= 2;
+    }
+
+    while !head.is_null() {
+        let (next, size) = unsafe { (*head as *mut usize, *head.add(1)) };
+        unsafe { dealloc(head as *mut u8, Layout::from_size_align(size, 8).unwrap()) };
+        head = next;
+    }
 }
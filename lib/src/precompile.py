@@ -0,0 +1,184 @@
+import dis
+import importlib.abc
+import importlib.machinery
+import importlib.util
+import os
+import sys
+import types
+
+
+class PrecompiledFinder(importlib.abc.MetaPathFinder, importlib.abc.Loader):
+    """Serve modules from code objects compiled while the plugin was built"""
+
+    def __init__(self, paths):
+        # Only searched while the plugin is built, see `finish`
+        self.paths = list(paths)
+        self.modules = {}
+        self.namespaces = {}
+
+    def add(self, name, path, is_package):
+        with open(path, "rb") as f:
+            source = f.read()
+        code = compile(source, path, "exec", dont_inherit=True)
+        self.modules[name] = (code, path, is_package)
+
+    def _search_locations(self, name):
+        parent = name.rpartition(".")[0]
+        if not parent:
+            return self.paths
+        self._locate(parent)
+        if parent in self.modules:
+            _, origin, is_package = self.modules[parent]
+            return [os.path.dirname(origin)] if is_package else []
+        return self.namespaces.get(parent, [])
+
+    def _locate(self, name):
+        """Compile `name` from the first location that has it as a module or a regular
+        package, otherwise record the directories that are portions of a namespace
+        package"""
+        if not self.paths or name in self.modules or name in self.namespaces:
+            return
+        portions = []
+        for location in self._search_locations(name):
+            base = os.path.join(location, name.rpartition(".")[2])
+            candidates = (
+                (os.path.join(base, "__init__.py"), True),
+                (base + ".py", False),
+            )
+            for path, is_package in candidates:
+                if os.path.isfile(path):
+                    try:
+                        self.add(name, path, is_package)
+                    except SyntaxError as exc:
+                        print(f"Skipping {path}: {exc}", file=sys.stderr)
+                    return
+            if os.path.isdir(base) and _has_modules(base):
+                portions.append(base)
+        if portions:
+            self.namespaces[name] = portions
+
+    def find_spec(self, name, path=None, target=None):
+        if _is_module_name(name):
+            self._locate(name)
+        if name not in self.modules:
+            return self._namespace_spec(name)
+        _, origin, is_package = self.modules[name]
+        spec = importlib.machinery.ModuleSpec(
+            name, self, origin=origin, is_package=is_package
+        )
+        spec.has_location = True
+        if is_package:
+            spec.submodule_search_locations = [os.path.dirname(origin)]
+        return spec
+
+    def _namespace_spec(self, name):
+        # Directories without an `__init__.py` are portions of a namespace package,
+        # a regular package or module with the same name takes precedence
+        if name not in self.namespaces:
+            return None
+        spec = importlib.machinery.ModuleSpec(name, self, is_package=True)
+        spec.submodule_search_locations = list(self.namespaces[name])
+        return spec
+
+    def create_module(self, spec):
+        return None
+
+    def exec_module(self, module):
+        # Namespace packages have no code
+        if module.__spec__.name in self.modules:
+            code, _, _ = self.modules[module.__spec__.name]
+            exec(code, module.__dict__)
+
+    def finish(self, plugin):
+        """Compile the modules that the plugin and the modules compiled so far import,
+        including imports inside functions, then stop searching the paths"""
+        pending = [(code, plugin.__package__) for code in _function_code(plugin)]
+        for name, (code, _, is_package) in self.modules.items():
+            pending.append((code, name if is_package else name.rpartition(".")[0]))
+
+        seen = set()
+        while pending:
+            code, package = pending.pop()
+            for name in _imports(code, package) - seen:
+                seen.add(name)
+                if name in self.modules or not _is_module_name(name):
+                    continue
+                self._locate(name)
+                if name in self.modules:
+                    found, _, is_package = self.modules[name]
+                    package = name if is_package else name.rpartition(".")[0]
+                    pending.append((found, package))
+        self.paths = []
+
+
+def _is_module_name(name):
+    return all(part.isidentifier() for part in name.split("."))
+
+
+def _has_modules(path):
+    """Whether the directory contains a module or a package that can be imported"""
+    for dirpath, dirnames, filenames in os.walk(path):
+        dirnames[:] = [d for d in dirnames if d.isidentifier()]
+        for filename in filenames:
+            if filename.endswith(".py") and filename[: -len(".py")].isidentifier():
+                return True
+    return False
+
+
+def _function_code(module):
+    """The code objects of the functions and classes defined in `module`, and code
+    objects it keeps in its namespace"""
+    namespace = vars(module)
+    codes = []
+    seen = set()
+    pending = list(namespace.values())
+    while pending:
+        value = pending.pop()
+        if id(value) in seen:
+            continue
+        seen.add(id(value))
+        if isinstance(value, types.CodeType):
+            codes.append(value)
+        elif isinstance(value, (staticmethod, classmethod)):
+            pending.append(value.__func__)
+        elif isinstance(value, property):
+            pending.extend((value.fget, value.fset, value.fdel))
+        elif isinstance(value, types.FunctionType) and value.__globals__ is namespace:
+            codes.append(value.__code__)
+        elif isinstance(value, type) and value.__module__ == module.__name__:
+            pending.extend(vars(value).values())
+    return codes
+
+
+def _imports(code, package):
+    """The names of the modules `code` may import, and of their parent packages"""
+    names = set()
+    codes = [code]
+    while codes:
+        code = codes.pop()
+        codes.extend(x for x in code.co_consts if isinstance(x, types.CodeType))
+        consts = []
+        for instr in dis.get_instructions(code):
+            if instr.opname == "LOAD_CONST":
+                consts.append(instr.argval)
+            elif instr.opname == "IMPORT_NAME" and len(consts) >= 2:
+                level, fromlist = consts[-2], consts[-1]
+                try:
+                    name = importlib.util.resolve_name(
+                        "." * level + instr.argval, package
+                    )
+                except (ImportError, TypeError, ValueError):
+                    continue
+                parts = name.split(".")
+                names.update(".".join(parts[:i]) for i in range(1, len(parts) + 1))
+                names.update(f"{name}.{item}" for item in fromlist or ())
+    return names
+
+
+def install(paths):
+    """Serve the modules found in `paths` from code compiled while the plugin is built,
+    ahead of the regular path finder. Only modules that are imported during the build,
+    or that `finish` finds imports of, are compiled"""
+    finder = PrecompiledFinder(paths)
+    sys.meta_path.insert(0, finder)
+    return finder
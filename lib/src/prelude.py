@@ -1,11 +1,54 @@
 from typing import Union, Optional
+from collections.abc import Mapping, MutableMapping
+import base64
+import io
 import json
+import sys
+import time
+import traceback
+import urllib.parse
+# The C module is always available, unlike `tracemalloc` which imports pickle
+import _tracemalloc
 from enum import Enum
 
 import extism_ffi as ffi
 
+__all__ = [
+    "LogLevel",
+    "input_str",
+    "input_length",
+    "input_bytes",
+    "output_str",
+    "output_bytes",
+    "memory",
+    "log",
+    "HttpRequest",
+    "Error",
+    "set_error",
+    "LimitExceeded",
+    "import_fn",
+    "plugin_fn",
+    "shared_fn",
+    "on_init",
+    "before_call",
+    "after_call",
+    "on_error",
+    "input_json",
+    "output_json",
+    "input",
+    "InputReader",
+    "output",
+    "Var",
+    "Config",
+    "config",
+    "Headers",
+    "HttpResponse",
+    "Http",
+]
+
 LogLevel = ffi.LogLevel
 input_str = ffi.input_str
+input_length = ffi.input_length
 input_bytes = ffi.input_bytes
 output_str = ffi.output_str
 output_bytes = ffi.output_bytes
@@ -20,10 +63,203 @@ def log(level, msg):
 
 HttpRequest = ffi.HttpRequest
 
-__exports = []
+__exports = {}
+
+__init_hooks = []
+__before_call = []
+__after_call = []
+__call_error = []
+
+# Config passed to `extism-py --config`, only set while the init hooks run
+_build_config = None
 
 IMPORT_INDEX = 0
 
+# Host calls made during the current export call, only set when built with --trace-calls
+_trace_host_calls = None
+
+# Set to "json" when built with `--error-format json`
+_error_format = "text"
+
+# How much of an exception reaches the host: "message", "type" or "traceback",
+# set with `--error-detail`
+_error_detail = "traceback"
+
+
+class Error(Exception):
+    """An exception with an error code, included in JSON error payloads"""
+
+    def __init__(self, message, code=None):
+        super().__init__(message)
+        self.code = code
+
+
+def set_error(error):
+    """Set the error returned to the host, a dict is encoded as JSON"""
+    if isinstance(error, dict):
+        error = json.dumps(error)
+    ffi.set_error(error)
+
+
+class LimitExceeded(BaseException):
+    """Raised when a call exceeds one of the limits set in the Extism config, the
+    code is the config key. Like `KeyboardInterrupt` it isn't caught by
+    `except Exception`"""
+
+    def __init__(self, key, limit):
+        super().__init__(f"Call exceeded {key} = {limit}")
+        self.code = key
+
+
+# Extism config keys limiting a single call, read by the first call
+_LIMIT_KEYS = (
+    "python.max_recursion_depth",
+    "python.max_heap",
+    "python.max_instructions",
+)
+_limit_values = None
+
+
+class _Limits:
+    """Enforce the limits from the Extism config while an export runs
+
+    The recursion depth is enforced with the interpreter's recursion limit, the heap
+    and instruction limits with a trace function that runs before every bytecode.
+    The heap is only checked between bytecodes, so a single large allocation made
+    by C code, like `bytearray(10**9)`, can still run the instance out of memory
+    before the limit is noticed.
+    """
+
+    def __init__(self, max_depth, max_heap, max_instructions):
+        self._max_depth = max_depth
+        self._max_heap = max_heap
+        self._max_instructions = max_instructions
+        # Single line loops only emit one line event, so both are checked per opcode
+        self._trace_opcodes = max_heap is not None or max_instructions is not None
+        self._instructions = 0
+        self._exceeded = None
+        self._previous = None
+        self._recursion_limit = None
+        self._stop_tracemalloc = False
+
+    @staticmethod
+    def from_config():
+        global _limit_values
+        if _limit_values is None:
+            values = []
+            for key in _LIMIT_KEYS:
+                value = ffi.config_get(key)
+                if value is not None:
+                    if not value.isdigit():
+                        raise Exception(f"Config key {key} must be an integer: {value!r}")
+                    value = int(value)
+                values.append(value)
+            _limit_values = tuple(values)
+        return _Limits(*_limit_values)
+
+    def _exceed(self, key, limit):
+        self._exceeded = LimitExceeded(key, limit)
+        raise self._exceeded
+
+    def _trace(self, frame, event, arg):
+        if event == "call":
+            # Leaving the `with` block is not part of the call
+            if frame.f_code is _Limits.__exit__.__code__:
+                return None
+            frame.f_trace_lines = False
+            frame.f_trace_opcodes = True
+        elif event == "opcode":
+            self._instructions += 1
+            if (
+                self._max_instructions is not None
+                and self._instructions > self._max_instructions
+            ):
+                self._exceed("python.max_instructions", self._max_instructions)
+            if (
+                self._max_heap is not None
+                and _tracemalloc.get_traced_memory()[0] > self._max_heap
+            ):
+                self._exceed("python.max_heap", self._max_heap)
+        return self._trace
+
+    def __enter__(self):
+        if self._max_depth is not None:
+            if self._max_depth == 0:
+                self._exceed("python.max_recursion_depth", self._max_depth)
+            # The frames below the `with` block don't count, the export itself is
+            # the first level
+            depth = 0
+            frame = sys._getframe(1)
+            while frame is not None:
+                depth += 1
+                frame = frame.f_back
+            self._recursion_limit = sys.getrecursionlimit()
+            sys.setrecursionlimit(depth + self._max_depth)
+
+        if not self._trace_opcodes:
+            return self
+        if self._max_heap is not None and not _tracemalloc.is_tracing():
+            _tracemalloc.start()
+            self._stop_tracemalloc = True
+        self._previous = sys.gettrace()
+        sys.settrace(self._trace)
+        return self
+
+    def __exit__(self, exc_type, exc, tb):
+        if self._recursion_limit is not None:
+            sys.setrecursionlimit(self._recursion_limit)
+            if isinstance(exc, RecursionError):
+                raise LimitExceeded(
+                    "python.max_recursion_depth", self._max_depth
+                ) from exc
+
+        if not self._trace_opcodes:
+            return False
+        sys.settrace(self._previous)
+        if self._stop_tracemalloc:
+            _tracemalloc.stop()
+        # CPython removes a trace function that raises, so the call fails even if the
+        # plugin caught the exception
+        if self._exceeded is not None and exc is not self._exceeded:
+            raise self._exceeded from exc
+        return False
+
+
+def _error_type(exc):
+    t = type(exc)
+    if t.__module__ == "builtins":
+        return t.__qualname__
+    return f"{t.__module__}.{t.__qualname__}"
+
+
+def _format_error(exc):
+    """Format an exception raised by an export for the host"""
+    tb = "".join(traceback.format_tb(exc.__traceback__))
+    if _error_detail != "traceback":
+        # The details are left out of the error, but still available to the developer
+        log(LogLevel.Debug, f"{_error_type(exc)}: {str(exc)}:\n{tb}")
+
+    if _error_format == "json":
+        error = {}
+        if _error_detail != "message":
+            error["type"] = _error_type(exc)
+        error["message"] = str(exc)
+        if _error_detail == "traceback":
+            error["frames"] = [
+                {"file": f.filename, "line": f.lineno, "function": f.name}
+                for f in traceback.extract_tb(exc.__traceback__)
+                if f.name != "__invoke"
+            ]
+        error["code"] = getattr(exc, "code", None)
+        return json.dumps(error, default=str)
+
+    if _error_detail == "message":
+        return str(exc)
+    elif _error_detail == "type":
+        return f"{_error_type(exc)}: {str(exc)}"
+    return f"{str(exc)}:\n{tb}"
+
+
 def _store(x) -> int:
     if isinstance(x, str):
         return ffi.memory.alloc(x.encode()).offset
@@ -73,7 +309,7 @@ def import_fn(module, name):
     idx = IMPORT_INDEX
 
     def inner(func):
-        def wrapper(*args):
+        def call(*args):
             args = [_store(a) for a in args]
             if "return" in func.__annotations__:
                 ret = func.__annotations__["return"]
@@ -82,6 +318,22 @@ def import_fn(module, name):
             else:
                 ffi.__invoke_host_func0(idx, *args)
 
+        def wrapper(*args):
+            if _trace_host_calls is None:
+                return call(*args)
+
+            start = time.perf_counter_ns()
+            try:
+                return call(*args)
+            finally:
+                _trace_host_calls.append(
+                    {
+                        "module": module,
+                        "name": name,
+                        "duration_ns": time.perf_counter_ns() - start,
+                    }
+                )
+
         return wrapper
 
     IMPORT_INDEX += 1
@@ -91,7 +343,7 @@ def import_fn(module, name):
 def plugin_fn(func):
     """Annotate a function that will be called by Extism"""
     global __exports
-    __exports.append(func)
+    __exports[func.__name__] = func
 
     def inner():
         return func()
@@ -102,7 +354,7 @@ def plugin_fn(func):
 def shared_fn(f):
     """Annotate a an export that won't be called directly by Extism"""
     global __exports
-    __exports.append(f)
+    __exports[f.__name__] = f
 
     def inner(*args):
         return f(*args)
@@ -110,6 +362,42 @@ def shared_fn(f):
     return inner
 
 
+def on_init(func):
+    """Annotate a function that runs once while the plugin is built"""
+    __init_hooks.append(func)
+    return func
+
+
+def before_call(func):
+    """Annotate a function that's called with the export name before every call"""
+    __before_call.append(func)
+    return func
+
+
+def after_call(func):
+    """Annotate a function that's called with the export name after every call,
+    including calls that raised an exception"""
+    __after_call.append(func)
+    return func
+
+
+def on_error(func):
+    """Annotate a function that's called with the export name and the exception
+    when a call raises"""
+    __call_error.append(func)
+    return func
+
+
+def _run_init_hooks(config):
+    global _build_config
+    _build_config = config
+    try:
+        for func in __init_hooks:
+            func()
+    finally:
+        _build_config = None
+
+
 def input_json(t: Optional[type] = None):
     """Get input as JSON"""
     if t is int or t is float:
@@ -143,6 +431,40 @@ def input(t: type = None):
         raise Exception(f"Unsupported type for input: {t}")
 
 
+class InputReader(io.RawIOBase):
+    """Read the input in chunks without copying all of it into memory, wrap it in
+    `io.TextIOWrapper` to read text"""
+
+    def __init__(self):
+        self._offset = 0
+
+    def readable(self):
+        return True
+
+    def seekable(self):
+        return True
+
+    def readinto(self, buffer):
+        n = ffi.input_read_into(self._offset, buffer)
+        self._offset += n
+        return n
+
+    def seek(self, offset, whence=io.SEEK_SET):
+        if whence == io.SEEK_CUR:
+            offset += self._offset
+        elif whence == io.SEEK_END:
+            offset += ffi.input_length()
+        elif whence != io.SEEK_SET:
+            raise ValueError(f"Invalid whence: {whence}")
+        if offset < 0:
+            raise ValueError(f"Negative seek position {offset}")
+        self._offset = offset
+        return offset
+
+    def tell(self):
+        return self._offset
+
+
 def output(x=None):
     if x is None:
         return
@@ -158,6 +480,69 @@ def output(x=None):
         raise Exception(f"Unsupported type for output: {type(x)}")
 
 
+class _LogWriter(io.TextIOBase):
+    """Forward lines written to stdout or stderr to the Extism log"""
+
+    def __init__(self, level, name):
+        self._level = level
+        self._name = name
+        self._buffer = ""
+
+    @property
+    def name(self):
+        return self._name
+
+    @property
+    def encoding(self):
+        return "utf-8"
+
+    def writable(self):
+        return True
+
+    def write(self, s):
+        lines = (self._buffer + s).split("\n")
+        self._buffer = lines.pop()
+        for line in lines:
+            ffi.log(self._level, line)
+        return len(s)
+
+    def flush(self):
+        if self._buffer:
+            ffi.log(self._level, self._buffer)
+            self._buffer = ""
+
+
+def _install_stdio(stdout_level, stderr_level):
+    sys.stdout.flush()
+    sys.stderr.flush()
+    sys.stdout = _LogWriter(getattr(LogLevel, stdout_level.capitalize()), "<stdout>")
+    sys.stderr = _LogWriter(getattr(LogLevel, stderr_level.capitalize()), "<stderr>")
+
+
+def _encode_value(value) -> bytes:
+    if isinstance(value, str):
+        return value.encode()
+    elif isinstance(value, bool):
+        return b"true" if value else b"false"
+    elif isinstance(value, (int, float)):
+        return str(value).encode()
+    elif isinstance(value, (bytes, bytearray, memoryview)):
+        return value
+    raise TypeError(f"Unsupported type for a var: {type(value)}")
+
+
+def _decode_value(value: Optional[str], t: type, default):
+    if value is None:
+        return default
+    if t is bool:
+        if value.strip().lower() in ("1", "true", "yes", "on"):
+            return True
+        if value.strip().lower() in ("", "0", "false", "no", "off"):
+            return False
+        raise ValueError(f"Invalid boolean: {value!r}")
+    return t(value)
+
+
 class Var:
     @staticmethod
     def get_bytes(key: str) -> Optional[bytes]:
@@ -181,27 +566,211 @@ class Var:
         return json.loads(x)
 
     @staticmethod
-    def set(key: str, value: Union[bytes, str]):
-        """Set a variable with a string or bytes value"""
-        if isinstance(value, str):
-            value = value.encode()
-        return ffi.var_set(key, value)
+    def get_int(key: str, default: Optional[int] = None) -> Optional[int]:
+        """Get variable as int, or `default` if it's not set"""
+        return _decode_value(Var.get_str(key), int, default)
+
+    @staticmethod
+    def get_float(key: str, default: Optional[float] = None) -> Optional[float]:
+        """Get variable as float, or `default` if it's not set"""
+        return _decode_value(Var.get_str(key), float, default)
+
+    @staticmethod
+    def get_bool(key: str, default: Optional[bool] = None) -> Optional[bool]:
+        """Get variable as bool, or `default` if it's not set"""
+        return _decode_value(Var.get_str(key), bool, default)
+
+    @staticmethod
+    def exists(key: str) -> bool:
+        """Check whether a variable is set. Extism has no call for this, the host
+        still copies the whole value into plugin memory, but it's freed right away
+        instead of being converted to a Python object"""
+        return ffi.var_exists(key)
+
+    @staticmethod
+    def set(key: str, value):
+        """Set a variable with a string, bytes, bool, int or float value, `None`
+        removes it"""
+        if value is None:
+            return ffi.var_set(key, None)
+        return ffi.var_set(key, _encode_value(value))
+
+    @staticmethod
+    def delete(key: str):
+        """Remove a variable"""
+        ffi.var_set(key, None)
 
 
 class Config:
     @staticmethod
     def get_str(key: str) -> Optional[str]:
         """Get a config value as string"""
+        if _build_config is not None:
+            return _build_config.get(key)
         return ffi.config_get(key)
 
+    @staticmethod
+    def get_bytes(key: str) -> Optional[bytes]:
+        """Get a config value as bytes"""
+        if _build_config is not None:
+            x = _build_config.get(key)
+            return None if x is None else x.encode()
+        return ffi.config_get_bytes(key)
+
     @staticmethod
     def get_json(key: str):
         """Get a config vakye as JSON"""
-        x = ffi.config_get(key)
+        x = Config.get_str(key)
         if x is None:
             return None
         return json.loads(x)
 
+    @staticmethod
+    def get_int(key: str, default: Optional[int] = None) -> Optional[int]:
+        """Get a config value as int, or `default` if it's not set"""
+        return _decode_value(Config.get_str(key), int, default)
+
+    @staticmethod
+    def get_float(key: str, default: Optional[float] = None) -> Optional[float]:
+        """Get a config value as float, or `default` if it's not set"""
+        return _decode_value(Config.get_str(key), float, default)
+
+    @staticmethod
+    def get_bool(key: str, default: Optional[bool] = None) -> Optional[bool]:
+        """Get a config value as bool, or `default` if it's not set"""
+        return _decode_value(Config.get_str(key), bool, default)
+
+    @staticmethod
+    def exists(key: str) -> bool:
+        """Check whether a config key is set. Extism has no call for this, the host
+        still copies the whole value into plugin memory, but it's freed right away
+        instead of being converted to a Python object"""
+        if _build_config is not None:
+            return key in _build_config
+        return ffi.config_exists(key)
+
+
+class _Vars(MutableMapping):
+    """Variables as a mapping of names to bytes. Extism can't list the names, so it
+    can't be iterated, measured or converted to a dict, and it's always true"""
+
+    get_str = staticmethod(Var.get_str)
+    get_json = staticmethod(Var.get_json)
+    get_int = staticmethod(Var.get_int)
+    get_float = staticmethod(Var.get_float)
+    get_bool = staticmethod(Var.get_bool)
+
+    def get(self, key: str, default=None):
+        """Get a variable as bytes, or `default` if it's not set"""
+        value = Var.get_bytes(key)
+        return default if value is None else value
+
+    def __getitem__(self, key):
+        value = Var.get_bytes(key)
+        if value is None:
+            raise KeyError(key)
+        return value
+
+    def __setitem__(self, key, value):
+        Var.set(key, value)
+
+    def __delitem__(self, key):
+        if not Var.exists(key):
+            raise KeyError(key)
+        Var.delete(key)
+
+    def __contains__(self, key):
+        return isinstance(key, str) and Var.exists(key)
+
+    def __bool__(self):
+        return True
+
+    def __iter__(self):
+        raise TypeError("Extism vars can't be listed")
+
+    def __len__(self):
+        raise TypeError("Extism vars can't be listed")
+
+
+class _Config(Mapping):
+    """Config as a mapping of keys to strings. Extism can't list the keys, so it
+    can't be iterated, measured or converted to a dict, and it's always true"""
+
+    get_bytes = staticmethod(Config.get_bytes)
+    get_json = staticmethod(Config.get_json)
+    get_int = staticmethod(Config.get_int)
+    get_float = staticmethod(Config.get_float)
+    get_bool = staticmethod(Config.get_bool)
+
+    def get(self, key: str, default=None):
+        """Get a config value as string, or `default` if it's not set"""
+        value = Config.get_str(key)
+        return default if value is None else value
+
+    def __getitem__(self, key):
+        value = Config.get_str(key)
+        if value is None:
+            raise KeyError(key)
+        return value
+
+    def __contains__(self, key):
+        return isinstance(key, str) and Config.exists(key)
+
+    def __bool__(self):
+        return True
+
+    def __iter__(self):
+        raise TypeError("Extism config keys can't be listed")
+
+    def __len__(self):
+        raise TypeError("Extism config keys can't be listed")
+
+
+# Left out of `__all__`, `from extism import *` would shadow the builtin
+vars = _Vars()
+config = _Config()
+
+
+class Headers(MutableMapping):
+    """Case-insensitive HTTP headers. Extism passes headers to and from the host as a
+    map, so a name has a single value: repeated headers are joined with commas"""
+
+    def __init__(self, headers=None):
+        self._headers = {}
+        if headers is None:
+            return
+        if isinstance(headers, Mapping):
+            headers = headers.items()
+        for name, value in headers:
+            entry = self._headers.get(name.lower())
+            if entry is None:
+                self[name] = value
+                continue
+            # Cookies are the one request header that isn't joined with commas
+            sep = "; " if name.lower() == "cookie" else ", "
+            self._headers[name.lower()] = (entry[0], f"{entry[1]}{sep}{value}")
+
+    def __getitem__(self, name):
+        entry = self._headers.get(name.lower())
+        if entry is None:
+            raise KeyError(name)
+        return entry[1]
+
+    def __setitem__(self, name, value):
+        self._headers[name.lower()] = (name, str(value))
+
+    def __delitem__(self, name):
+        del self._headers[name.lower()]
+
+    def __iter__(self):
+        return (name for name, _ in self._headers.values())
+
+    def __len__(self):
+        return len(self._headers)
+
+    def __repr__(self):
+        return f"Headers({dict(self.items())})"
+
 
 class HttpResponse:
     _inner: ffi.HttpResponse
@@ -226,9 +795,22 @@ class HttpResponse:
         """Get response body JSON"""
         return json.loads(self.data_str())
 
-    def headers(self):
-        """Get HTTP response headers"""
-        return self._inner.headers or {}
+    def headers(self) -> Headers:
+        """Get HTTP response headers. The host passes one value per name, repeated
+        headers like `Set-Cookie` are joined or only one of them is kept, depending
+        on the host"""
+        return Headers(self._inner.headers or {})
+
+
+def _json_body(value) -> bytes:
+    return json.dumps(value).encode()
+
+
+def _auth_header(auth) -> str:
+    if isinstance(auth, str):
+        return f"Bearer {auth}"
+    user, password = auth
+    return "Basic " + base64.b64encode(f"{user}:{password}".encode()).decode()
 
 
 class Http:
@@ -237,10 +819,31 @@ class Http:
         url: str,
         meth: str = "GET",
         body: Optional[Union[bytes, str]] = None,
-        headers: Optional[dict] = None,
+        headers=None,
+        *,
+        json=None,
+        params=None,
+        auth=None,
     ) -> HttpResponse:
-        """Make an HTTP request"""
-        req = HttpRequest(url, meth, headers or {})
+        """Make an HTTP request
+
+        `headers` is a dict or a list of pairs, a name that is repeated is sent once
+        with the values joined by commas. `json` is encoded as the body, `params` is appended to the URL
+        as the query string and `auth` is a `(user, password)` tuple for basic or a
+        token for bearer authentication"""
+        headers = Headers(headers)
+        if json is not None:
+            if body is not None:
+                raise ValueError("Pass either body or json, not both")
+            body = _json_body(json)
+            headers.setdefault("Content-Type", "application/json")
+        if params:
+            query = urllib.parse.urlencode(params, doseq=True)
+            url += ("&" if "?" in url else "?") + query
+        if auth is not None:
+            headers["Authorization"] = _auth_header(auth)
         if body is not None and isinstance(body, str):
             body = body.encode()
+
+        req = HttpRequest(url, meth, dict(headers.items()))
         return HttpResponse(ffi.http_request(req, body))
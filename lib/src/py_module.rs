@@ -1,4 +1,5 @@
 use pyo3::{
+    buffer::PyBuffer,
     exceptions::PyException,
     prelude::*,
     types::{PyBytes, PyInt, PyModule, PyTuple},
@@ -11,10 +12,67 @@ fn error(x: extism_pdk::Error) -> PyErr {
     PyException::new_err(format!("{:?}", x))
 }
 
+// The contents of a buffer-protocol object, borrowed for as long as `buffer` is held
+fn buffer_slice(buffer: &PyBuffer<u8>) -> PyResult<&[u8]> {
+    if !buffer.is_c_contiguous() {
+        return Err(PyException::new_err("Expected a contiguous buffer"));
+    }
+    if buffer.len_bytes() == 0 {
+        return Ok(&[]);
+    }
+    Ok(unsafe { std::slice::from_raw_parts(buffer.buf_ptr() as *const u8, buffer.len_bytes()) })
+}
+
+fn buffer_slice_mut(buffer: &mut PyBuffer<u8>) -> PyResult<&mut [u8]> {
+    if buffer.readonly() || !buffer.is_c_contiguous() {
+        return Err(PyException::new_err(
+            "Expected a writable, contiguous buffer",
+        ));
+    }
+    if buffer.len_bytes() == 0 {
+        return Ok(&mut []);
+    }
+    Ok(unsafe { std::slice::from_raw_parts_mut(buffer.buf_ptr() as *mut u8, buffer.len_bytes()) })
+}
+
+// Copy the input starting at `offset` into `dest`, without the intermediate `Vec` made
+// by `extism_pdk::input`
+fn read_input(offset: u64, dest: &mut [u8]) {
+    let mut offs = offset;
+    let mut chunks = dest.chunks_exact_mut(8);
+    for chunk in &mut chunks {
+        let x = unsafe { extism_pdk::extism::input_load_u64(offs) };
+        chunk.copy_from_slice(&x.to_le_bytes());
+        offs += 8;
+    }
+    for byte in chunks.into_remainder() {
+        *byte = unsafe { extism_pdk::extism::input_load_u8(offs) };
+        offs += 1;
+    }
+}
+
+#[pyo3::pyfunction]
+pub fn input_length() -> u64 {
+    unsafe { extism_pdk::extism::input_length() }
+}
+
 #[pyo3::pyfunction]
 pub fn input_bytes(py: Python<'_>) -> PyResult<Bound<'_, PyBytes>> {
-    let input = extism_pdk::input::<Vec<u8>>().map_err(error)?;
-    Ok(PyBytes::new_bound(py, &input))
+    let length = input_length() as usize;
+    PyBytes::new_bound_with(py, length, |dest| {
+        read_input(0, dest);
+        Ok(())
+    })
+}
+
+/// Copy the input starting at `offset` into a writable buffer, returns the number of
+/// bytes copied
+#[pyo3::pyfunction]
+pub fn input_read_into(offset: u64, mut buffer: PyBuffer<u8>) -> PyResult<usize> {
+    let dest = buffer_slice_mut(&mut buffer)?;
+    let n = (input_length().saturating_sub(offset) as usize).min(dest.len());
+    read_input(offset, &mut dest[..n]);
+    Ok(n)
 }
 
 #[pyo3::pyfunction]
@@ -44,25 +102,74 @@ pub fn set_error(msg: &str) -> PyResult<()> {
     Ok(())
 }
 
+// Look up `key` with one of the kernel's `config_get`/`var_get` functions, the returned
+// block has to be freed by the caller
+fn lookup(key: &str, get: impl FnOnce(u64) -> u64) -> PyResult<Option<extism_pdk::Memory>> {
+    let key = extism_pdk::Memory::from_bytes(key).map_err(error)?;
+    let offset = get(key.offset());
+    key.free();
+    Ok(extism_pdk::Memory::find(offset))
+}
+
+fn lookup_bytes<'a>(
+    py: Python<'a>,
+    key: &str,
+    get: impl FnOnce(u64) -> u64,
+) -> PyResult<Option<Bound<'a, PyBytes>>> {
+    let Some(mem) = lookup(key, get)? else {
+        return Ok(None);
+    };
+    let bytes = PyBytes::new_bound_with(py, mem.len(), |dest| {
+        mem.load(dest);
+        Ok(())
+    });
+    mem.free();
+    bytes.map(Some)
+}
+
+fn lookup_exists(key: &str, get: impl FnOnce(u64) -> u64) -> PyResult<bool> {
+    Ok(lookup(key, get)?.map(|mem| mem.free()).is_some())
+}
+
 #[pyo3::pyfunction]
 pub fn config_get(key: &str) -> PyResult<Option<String>> {
     let r = extism_pdk::config::get(key).map_err(error)?;
     Ok(r)
 }
 
+#[pyo3::pyfunction]
+pub fn config_get_bytes<'a>(py: Python<'a>, key: &str) -> PyResult<Option<Bound<'a, PyBytes>>> {
+    lookup_bytes(py, key, |key| unsafe {
+        extism_pdk::extism::config_get(key)
+    })
+}
+
+#[pyo3::pyfunction]
+pub fn config_exists(key: &str) -> PyResult<bool> {
+    lookup_exists(key, |key| unsafe { extism_pdk::extism::config_get(key) })
+}
+
 #[pyo3::pyfunction]
 pub fn var_get<'a>(py: Python<'a>, key: &'a str) -> PyResult<Option<Bound<'a, PyBytes>>> {
-    let r: Option<Vec<u8>> = extism_pdk::var::get(key).map_err(error)?;
-    if let Some(r) = r {
-        Ok(Some(PyBytes::new_bound(py, &r)))
-    } else {
-        Ok(None)
-    }
+    lookup_bytes(py, key, |key| unsafe { extism_pdk::extism::var_get(key) })
 }
 
 #[pyo3::pyfunction]
-pub fn var_set(key: String, value: &[u8]) -> PyResult<()> {
-    extism_pdk::var::set(key, value).map_err(error)?;
+pub fn var_exists(key: &str) -> PyResult<bool> {
+    lookup_exists(key, |key| unsafe { extism_pdk::extism::var_get(key) })
+}
+
+/// Set a var, `None` removes it
+#[pyo3::pyfunction]
+pub fn var_set(key: String, value: Option<PyBuffer<u8>>) -> PyResult<()> {
+    match value {
+        Some(value) => extism_pdk::var::set(key, buffer_slice(&value)?).map_err(error)?,
+        None => {
+            let key = extism_pdk::Memory::from_bytes(key).map_err(error)?;
+            unsafe { extism_pdk::extism::var_set(key.offset(), 0) };
+            key.free();
+        }
+    }
     Ok(())
 }
 
@@ -96,6 +203,7 @@ pub struct HttpRequest {
     pub url: String,
     #[pyo3(get)]
     pub method: Option<String>,
+    // Extism sends headers to the host as a map, so each name has a single value
     #[pyo3(get)]
     pub headers: Option<HashMap<String, String>>,
 }
@@ -122,6 +230,9 @@ impl HttpRequest {
 pub struct HttpResponse {
     pub data: Vec<u8>,
     pub status: u16,
+    // The host returns one value per name, repeated response headers are joined or
+    // only one of them is kept, depending on the host
+    #[pyo3(get)]
     pub headers: HashMap<String, String>,
 }
 
@@ -193,7 +304,54 @@ pub fn memory_bytes(py: Python<'_>, mem: MemoryHandle) -> PyResult<Bound<'_, PyB
         length: mem.length,
     });
 
-    Ok(PyBytes::new_bound(py, &mem.to_vec()))
+    PyBytes::new_bound_with(py, mem.len(), |dest| {
+        mem.load(dest);
+        Ok(())
+    })
+}
+
+// The part of `mem` starting at `offset` that's at most `length` bytes long
+fn memory_range(mem: MemoryHandle, offset: u64, length: usize) -> PyResult<extism_pdk::Memory> {
+    if offset > mem.length {
+        return Err(PyException::new_err(format!(
+            "Offset {offset} is out of bounds for a block of {} bytes",
+            mem.length
+        )));
+    }
+    Ok(extism_pdk::Memory(extism_pdk::MemoryHandle {
+        offset: mem.offset + offset,
+        length: (length as u64).min(mem.length - offset),
+    }))
+}
+
+/// Copy a block into a writable buffer, returns the number of bytes copied
+#[pyo3::pyfunction]
+#[pyo3(name = "read_into", signature = (mem, buffer, offset=0))]
+pub fn memory_read_into(
+    mem: MemoryHandle,
+    mut buffer: PyBuffer<u8>,
+    offset: u64,
+) -> PyResult<usize> {
+    let dest = buffer_slice_mut(&mut buffer)?;
+    let mem = memory_range(mem, offset, dest.len())?;
+    mem.load(&mut dest[..mem.len()]);
+    Ok(mem.len())
+}
+
+/// Copy a buffer into a block, starting at `offset`
+#[pyo3::pyfunction]
+#[pyo3(name = "write", signature = (mem, data, offset=0))]
+pub fn memory_write(mem: MemoryHandle, data: PyBuffer<u8>, offset: u64) -> PyResult<()> {
+    let data = buffer_slice(&data)?;
+    let mem = memory_range(mem, offset, data.len())?;
+    if mem.len() < data.len() {
+        return Err(PyException::new_err(format!(
+            "Writing {} bytes at offset {offset} doesn't fit in the block",
+            data.len()
+        )));
+    }
+    mem.store(data);
+    Ok(())
 }
 
 #[pyo3::pyfunction]
@@ -221,8 +379,8 @@ pub fn memory_free(mem: MemoryHandle) -> PyResult<()> {
 
 #[pyo3::pyfunction]
 #[pyo3(name = "alloc")]
-pub fn memory_alloc(data: &[u8]) -> PyResult<MemoryHandle> {
-    let mem = extism_pdk::Memory::from_bytes(data).map_err(error)?;
+pub fn memory_alloc(data: PyBuffer<u8>) -> PyResult<MemoryHandle> {
+    let mem = extism_pdk::Memory::from_bytes(buffer_slice(&data)?).map_err(error)?;
     Ok(MemoryHandle {
         offset: mem.offset(),
         length: mem.len() as u64,
@@ -331,6 +489,8 @@ pub fn make_extism_ffi_module(py: Python<'_>, module: &Bound<'_, PyModule>) -> P
     memory_module.add_class::<MemoryHandle>()?;
     memory_module.add_function(pyo3::wrap_pyfunction!(memory_find, &memory_module)?)?;
     memory_module.add_function(pyo3::wrap_pyfunction!(memory_bytes, &memory_module)?)?;
+    memory_module.add_function(pyo3::wrap_pyfunction!(memory_read_into, &memory_module)?)?;
+    memory_module.add_function(pyo3::wrap_pyfunction!(memory_write, &memory_module)?)?;
     memory_module.add_function(pyo3::wrap_pyfunction!(memory_string, &memory_module)?)?;
     memory_module.add_function(pyo3::wrap_pyfunction!(memory_free, &memory_module)?)?;
     memory_module.add_function(pyo3::wrap_pyfunction!(memory_alloc, &memory_module)?)?;
@@ -338,12 +498,17 @@ pub fn make_extism_ffi_module(py: Python<'_>, module: &Bound<'_, PyModule>) -> P
     module.add_class::<LogLevel>()?;
     module.add_class::<HttpRequest>()?;
     module.add_class::<HttpResponse>()?;
+    module.add_function(pyo3::wrap_pyfunction!(input_length, module)?)?;
     module.add_function(pyo3::wrap_pyfunction!(input_bytes, module)?)?;
+    module.add_function(pyo3::wrap_pyfunction!(input_read_into, module)?)?;
     module.add_function(pyo3::wrap_pyfunction!(output_bytes, module)?)?;
     module.add_function(pyo3::wrap_pyfunction!(input_str, module)?)?;
     module.add_function(pyo3::wrap_pyfunction!(output_str, module)?)?;
     module.add_function(pyo3::wrap_pyfunction!(config_get, module)?)?;
+    module.add_function(pyo3::wrap_pyfunction!(config_get_bytes, module)?)?;
+    module.add_function(pyo3::wrap_pyfunction!(config_exists, module)?)?;
     module.add_function(pyo3::wrap_pyfunction!(var_get, module)?)?;
+    module.add_function(pyo3::wrap_pyfunction!(var_exists, module)?)?;
     module.add_function(pyo3::wrap_pyfunction!(var_set, module)?)?;
     module.add_function(pyo3::wrap_pyfunction!(log, module)?)?;
     module.add_function(pyo3::wrap_pyfunction!(set_error, module)?)?;
@@ -0,0 +1,91 @@
+import gc
+import importlib
+import sys
+import time
+
+CLOCKS = [
+    "time",
+    "time_ns",
+    "monotonic",
+    "monotonic_ns",
+    "perf_counter",
+    "perf_counter_ns",
+]
+
+_frozen = False
+
+# The `datetime` module and its original `datetime` class while it's replaced
+_datetime = None
+
+
+def _fixed_clock(name, value):
+    original = getattr(time, name)
+
+    # References taken during initialization, like `from time import time`, keep
+    # working with the real clock once the snapshot has been taken
+    def clock():
+        if _frozen:
+            return value
+        return original()
+
+    return clock
+
+
+def _fixed_datetime(module, epoch):
+    base = module.datetime
+
+    # `date.today()` goes through `time.time`, but `datetime.now()` and
+    # `datetime.utcnow()` read the system clock directly
+    class datetime(base):
+        @classmethod
+        def now(cls, tz=None):
+            if not _frozen:
+                return base.now(tz)
+            now = base.fromtimestamp(epoch, module.timezone.utc)
+            return now.replace(tzinfo=None) if tz is None else now.astimezone(tz)
+
+        @classmethod
+        def utcnow(cls):
+            if not _frozen:
+                return base.utcnow()
+            return base.fromtimestamp(epoch, module.timezone.utc).replace(tzinfo=None)
+
+    datetime.__module__ = base.__module__
+    datetime.__qualname__ = base.__qualname__
+    return datetime
+
+
+def install(epoch):
+    """Freeze the clock while the plugin is initialized"""
+    global _frozen, _datetime
+    _frozen = True
+    for name in CLOCKS:
+        if name.endswith("_ns"):
+            value = epoch * 1_000_000_000 if name == "time_ns" else 0
+        else:
+            value = float(epoch) if name == "time" else 0.0
+        setattr(time, name, _fixed_clock(name, value))
+
+    import datetime
+
+    _datetime = (datetime, datetime.datetime)
+    datetime.datetime = _fixed_datetime(datetime, epoch)
+
+
+def finish():
+    """Reset state that depends on the build machine before the snapshot is taken"""
+    global _frozen, _datetime
+    _frozen = False
+
+    if _datetime is not None:
+        module, base = _datetime
+        module.datetime = base
+        _datetime = None
+
+    random = sys.modules.get("random")
+    if random is not None:
+        random.seed(0)
+
+    sys.path_importer_cache.clear()
+    importlib.invalidate_caches()
+    gc.collect()
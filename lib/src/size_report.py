@@ -0,0 +1,52 @@
+import gc
+import sys
+import types
+
+
+def _retained(roots, seen):
+    total = 0
+    pending = list(roots)
+    while pending:
+        obj = pending.pop()
+        if id(obj) in seen or isinstance(obj, type(sys)):
+            continue
+        seen.add(id(obj))
+        total += sys.getsizeof(obj)
+        pending.extend(gc.get_referents(obj))
+    return total
+
+
+def report(path):
+    """Write the memory retained by each loaded module to `path`
+
+    Objects reachable from several modules are counted once, for the first module
+    that reaches them. Interpreter internals reachable from `builtins` and `sys`
+    are counted for those modules, the other modules are then visited in
+    alphabetical order, so a helper shared by `a` and `b` is charged to `a`.
+    Entries of `sys.modules` that aren't modules, like the `None` placeholders of
+    failed imports, are skipped.
+    """
+    modules = {
+        name: module
+        for name, module in sys.modules.items()
+        if isinstance(module, types.ModuleType) and hasattr(module, "__dict__")
+    }
+    seen = {id(module.__dict__) for module in modules.values()}
+    names = ["builtins", "sys"]
+    names += sorted(name for name in modules if name not in names)
+
+    sizes = []
+    for name in names:
+        module = modules.get(name)
+        if module is None:
+            continue
+        size = sys.getsizeof(module) + _retained(vars(module).values(), seen)
+        sizes.append((size, name))
+    sizes.sort(reverse=True)
+
+    with open(path, "w") as f:
+        for size, name in sizes:
+            f.write(f"{name}\t{size}\n")
+
+    del seen, sizes
+    gc.collect()
@@ -0,0 +1,273 @@
+import builtins
+import collections
+import gc
+import importlib
+import sys
+import types
+
+# Modules the interpreter itself relies on
+ALWAYS_KEEP = {
+    "__main__",
+    "_frozen_importlib",
+    "_frozen_importlib_external",
+    "zipimport",
+}
+
+# Objects that are part of a module when they are reached from its namespace
+_PARTS = (
+    dict,
+    list,
+    tuple,
+    set,
+    frozenset,
+    types.CellType,
+    types.MethodType,
+    property,
+    staticmethod,
+    classmethod,
+    types.GetSetDescriptorType,
+    types.MemberDescriptorType,
+)
+
+_edges = {}
+_import = builtins.__import__
+_import_module = importlib.import_module
+
+
+def _record(importer, name):
+    if importer is not None:
+        _edges.setdefault(importer, set()).add(name)
+
+
+def _traced_import(name, globals=None, locals=None, fromlist=(), level=0):
+    module = _import(name, globals, locals, fromlist, level)
+    # `__import__` called without globals is attributed to the calling module
+    if globals is None:
+        globals = sys._getframe(1).f_globals
+    importer = globals.get("__name__")
+
+    _record(importer, module.__name__)
+    if level == 0 and not fromlist:
+        _record(importer, name)
+    for item in fromlist or ():
+        attr = getattr(module, item, None)
+        if isinstance(attr, types.ModuleType):
+            _record(importer, attr.__name__)
+    return module
+
+
+def _traced_import_module(name, package=None):
+    module = _import_module(name, package)
+    _record(sys._getframe(1).f_globals.get("__name__"), module.__name__)
+    return module
+
+
+def install():
+    """Start recording which modules import each other"""
+    builtins.__import__ = _traced_import
+    importlib.import_module = _traced_import_module
+
+
+def _is_kept(name):
+    return (
+        name in ALWAYS_KEEP
+        or name in sys.builtin_module_names
+        or name == "encodings"
+        or name.startswith("encodings.")
+    )
+
+
+def _lazy_imports(module):
+    """Modules imported inside the functions of `module`, they are only imported once
+    the function runs, which may be after the snapshot has been taken"""
+    import dis
+    import importlib.util
+
+    namespace = vars(module)
+    codes = []
+    seen = set()
+    pending = list(namespace.values())
+    while pending:
+        value = pending.pop()
+        if id(value) in seen:
+            continue
+        seen.add(id(value))
+        if isinstance(value, (staticmethod, classmethod)):
+            pending.append(value.__func__)
+        elif isinstance(value, property):
+            pending.extend((value.fget, value.fset, value.fdel))
+        elif isinstance(value, types.FunctionType) and value.__globals__ is namespace:
+            codes.append(value.__code__)
+        elif isinstance(value, type) and value.__module__ == module.__name__:
+            pending.extend(vars(value).values())
+
+    names = set()
+    while codes:
+        code = codes.pop()
+        codes.extend(x for x in code.co_consts if isinstance(x, types.CodeType))
+        consts = []
+        for instr in dis.get_instructions(code):
+            if instr.opname == "LOAD_CONST":
+                consts.append(instr.argval)
+            elif instr.opname == "IMPORT_NAME" and len(consts) >= 2:
+                level, fromlist = consts[-2], consts[-1]
+                try:
+                    name = importlib.util.resolve_name(
+                        "." * level + instr.argval, module.__package__
+                    )
+                except (ImportError, TypeError, ValueError):
+                    continue
+                names.add(name)
+                names.update(f"{name}.{item}" for item in fromlist or ())
+    return names
+
+
+def _reachable(roots):
+    """Names of the modules reachable from `roots` through imports, including imports
+    inside functions, and module attributes"""
+    keep = set()
+    pending = list(roots)
+    while pending:
+        name = pending.pop()
+        module = sys.modules.get(name)
+        if name in keep or not isinstance(module, types.ModuleType):
+            continue
+        keep.add(name)
+
+        parent = name.rpartition(".")[0]
+        if parent:
+            pending.append(parent)
+        pending.extend(_edges.get(name, ()))
+        pending.extend(_lazy_imports(module))
+        for value in list(vars(module).values()):
+            if isinstance(value, types.ModuleType):
+                pending.append(value.__name__)
+    return keep
+
+
+def _is_part(obj, module):
+    """Whether `obj` only exists for `module`"""
+    if isinstance(obj, types.ModuleType) or obj is vars(builtins):
+        return False
+    if isinstance(obj, types.FunctionType):
+        return obj.__globals__ is vars(module)
+    if isinstance(obj, type):
+        return obj.__module__ == module.__name__
+    if isinstance(obj, _PARTS):
+        return gc.is_tracked(obj)
+    return _is_part(type(obj), module)
+
+
+def _parts(candidates):
+    """Collect the objects that belong to each candidate module, returns the objects by
+    id and the ids belonging to each candidate"""
+    parts = {}
+    groups = {}
+    for names in candidates:
+        module = sys.modules[names[0]]
+        group = groups[names] = {id(module)}
+        parts[id(module)] = module
+        pending = gc.get_referents(module)
+        while pending:
+            obj = pending.pop()
+            if id(obj) in parts or not _is_part(obj, module):
+                continue
+            parts[id(obj)] = obj
+            group.add(id(obj))
+            pending.extend(gc.get_referents(obj))
+    return parts, groups
+
+
+def _internal_refs(parts, candidates):
+    """Count the references to each part that come from other parts, from `sys.modules`
+    and from the attributes of the parent packages the candidates are removed from"""
+    internal = collections.Counter()
+    for obj in parts.values():
+        for ref in gc.get_referents(obj):
+            internal[id(ref)] += 1
+
+    for names in candidates:
+        for name in names:
+            module = sys.modules[name]
+            internal[id(module)] += 1
+            parent, _, child = name.rpartition(".")
+            parent = sys.modules.get(parent)
+            if (
+                isinstance(parent, types.ModuleType)
+                and id(parent) not in parts
+                and vars(parent).get(child) is module
+            ):
+                internal[id(module)] += 1
+    return internal
+
+
+def _referenced(parts, internal):
+    """Ids of the parts that something besides the other parts refers to, including
+    references held by native code that the garbage collector can't see"""
+    # The references held while counting, measured on an object only `parts` holds
+    parts[None] = []
+    baseline = sys.getrefcount(parts[None])
+    del parts[None]
+
+    return {
+        key
+        for key in list(parts)
+        if sys.getrefcount(parts[key]) - baseline > internal[key]
+    }
+
+
+def _unreferenced(candidates):
+    """Remove the candidates that are still in use, until every remaining one is only
+    referred to by `sys.modules` and the other remaining candidates"""
+    while candidates:
+        parts, groups = _parts(candidates)
+        referenced = _referenced(parts, _internal_refs(parts, candidates))
+        del parts
+        used = {names for names, group in groups.items() if group & referenced}
+        if not used:
+            break
+        candidates = [names for names in candidates if names not in used]
+    return candidates
+
+
+def _candidates(keep):
+    """The names of each module that isn't reachable, a module can be listed under
+    several names, like `os.path`, and is only a candidate when none of them is kept"""
+    aliases = {}
+    for name, module in sys.modules.items():
+        if isinstance(module, types.ModuleType):
+            aliases.setdefault(id(module), []).append(name)
+    return [
+        tuple(names)
+        for names in aliases.values()
+        if not any(name in keep or _is_kept(name) for name in names)
+    ]
+
+
+def _drop(name):
+    parent, _, child = name.rpartition(".")
+    parent = sys.modules.get(parent)
+    module = sys.modules.pop(name)
+    if isinstance(parent, types.ModuleType) and vars(parent).get(child) is module:
+        delattr(parent, child)
+
+
+def shake(roots):
+    """Drop the modules that aren't reachable from `roots` and that nothing refers to
+    anymore, then compact the import caches"""
+    builtins.__import__ = _import
+    importlib.import_module = _import_module
+
+    candidates = _candidates(_reachable(roots))
+    dropped = sorted(name for names in _unreferenced(candidates) for name in names)
+    for name in dropped:
+        _drop(name)
+
+    sys.path_importer_cache.clear()
+    importlib.invalidate_caches()
+    gc.collect()
+
+    print(
+        f"Tree shaking dropped {len(dropped)} modules: {', '.join(dropped)}",
+        file=sys.stderr,
+    )
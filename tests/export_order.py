@@ -0,0 +1,24 @@
+# Each export returns its own name, even though they are registered in a different
+# order than they appear in the source
+import extism
+
+
+@extism.plugin_fn
+def alpha():
+    extism.output_str("alpha")
+
+
+@extism.plugin_fn
+def beta():
+    extism.output_str("beta")
+
+
+@extism.plugin_fn
+def gamma():
+    extism.output_str("gamma")
+
+
+# Move alpha and beta to the end of the registry, as happens when exports are
+# decorated from a helper module or registered again
+for name in ["alpha", "beta"]:
+    extism.__exports[name] = extism.__exports.pop(name)
@@ -0,0 +1,60 @@
+# Checks the HTTP API against tests/http_server.py, the base URL is the input
+import http.client
+import json
+import urllib.parse
+import urllib.request
+
+import extism
+
+
+@extism.plugin_fn
+def test_http():
+    base = extism.input_str()
+
+    headers = extism.Headers([("X-Tag", "a"), ("x-tag", "b")])
+    res = extism.Http.request(
+        f"{base}/echo?fixed=1",
+        "POST",
+        headers=headers,
+        json={"n": 1},
+        params={"q": "a b", "list": ["x", "y"]},
+        auth=("user", "pass"),
+    )
+    assert res.status_code == 200, res.status_code
+    echo = res.data_json()
+    assert echo["method"] == "POST", echo
+    assert echo["path"] == "/echo?fixed=1&q=a+b&list=x&list=y", echo
+    assert echo["headers"]["x-tag"] == "a, b", echo
+    assert echo["headers"]["authorization"] == "Basic dXNlcjpwYXNz", echo
+    assert echo["headers"]["content-type"] == "application/json", echo
+    assert echo["body"] == '{"n": 1}', echo
+
+    res_headers = res.headers()
+    assert res_headers["x-echo"] == "yes", res_headers
+    assert res_headers.get("X-ECHO") == "yes", res_headers
+    assert "content-TYPE" in res_headers, res_headers
+
+    echo = extism.Http.request(f"{base}/token", auth="secret").data_json()
+    assert echo["method"] == "GET", echo
+    assert echo["headers"]["authorization"] == "Bearer secret", echo
+
+    # urllib.request and http.client are routed through Extism HTTP
+    with urllib.request.urlopen(f"{base}/urllib", data=b"x=1") as res:
+        assert res.status == 200, res.status
+        assert res.headers["X-Echo"] == "yes", res.headers
+        echo = json.loads(res.read())
+    assert echo["method"] == "POST", echo
+    assert echo["body"] == "x=1", echo
+
+    url = urllib.parse.urlsplit(base)
+    conn = http.client.HTTPConnection(url.hostname, url.port)
+    conn.request("PUT", "/client", body="hello", headers={"X-Tag": "c"})
+    res = conn.getresponse()
+    assert res.status == 200 and res.reason == "OK", (res.status, res.reason)
+    assert res.getheader("x-echo") == "yes", res.getheaders()
+    echo = json.loads(res.read())
+    assert echo["path"] == "/client", echo
+    assert echo["headers"]["x-tag"] == "c", echo
+    assert echo["body"] == "hello", echo
+
+    extism.output_str("ok")
@@ -0,0 +1,32 @@
+"""A local stand-in for an HTTP API, replies to every request with a JSON
+description of the request it received"""
+
+import json
+import sys
+from http.server import BaseHTTPRequestHandler, HTTPServer
+
+
+class EchoHandler(BaseHTTPRequestHandler):
+    def echo(self):
+        length = int(self.headers.get("Content-Length") or 0)
+        body = self.rfile.read(length).decode()
+        headers = {}
+        for name in self.headers.keys():
+            headers[name.lower()] = ", ".join(self.headers.get_all(name))
+        reply = json.dumps(
+            {"method": self.command, "path": self.path, "headers": headers, "body": body}
+        ).encode()
+
+        self.send_response(200)
+        self.send_header("Content-Type", "application/json")
+        self.send_header("X-Echo", "yes")
+        self.send_header("Content-Length", str(len(reply)))
+        self.end_headers()
+        self.wfile.write(reply)
+
+    do_GET = do_POST = do_PUT = do_DELETE = echo
+
+
+if __name__ == "__main__":
+    port = int(sys.argv[1]) if len(sys.argv) > 1 else 8181
+    HTTPServer(("127.0.0.1", port), EchoHandler).serve_forever()
@@ -0,0 +1,2 @@
+# `nspkg` has no __init__.py, it's a namespace package
+NAME = "nspkg.mod"
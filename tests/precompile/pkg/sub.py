@@ -0,0 +1,3 @@
+from . import NAME as PARENT
+
+NAME = f"{PARENT}.sub"
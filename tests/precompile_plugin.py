@@ -0,0 +1,11 @@
+# Built with --precompile and tests/precompile on PYTHONPATH, the modules are only
+# imported once the export is called and PYTHONPATH isn't available anymore
+import extism
+
+
+@extism.plugin_fn
+def precompiled():
+    import nspkg.mod
+    import pkg.sub
+
+    extism.output_str(f"{pkg.sub.NAME} {nspkg.mod.NAME}")
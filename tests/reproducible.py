@@ -0,0 +1,13 @@
+# Built twice with --reproducible, from different directories and with PYTHONPATH
+# in different places, both builds have to be identical
+import datetime
+
+import extism
+import pkg.sub
+
+BUILT = datetime.datetime.now().isoformat()
+
+
+@extism.plugin_fn
+def built():
+    extism.output_str(f"{BUILT} {pkg.sub.NAME}")
@@ -0,0 +1,21 @@
+# Built with --tree-shake: `calendar` is only used while the plugin is built and is
+# dropped, `datetime` is imported lazily when the export is called
+import extism
+
+
+def _month_names():
+    # Runs in its own namespace, so nothing refers to `calendar` afterwards
+    namespace = {"__name__": "month_names"}
+    exec("import calendar\nnames = list(calendar.month_name)[1:]", namespace)
+    return namespace["names"]
+
+
+MONTHS = _month_names()
+
+
+@extism.plugin_fn
+def month():
+    import datetime
+
+    date = datetime.date.fromisoformat(extism.input_str())
+    extism.output_str(MONTHS[date.month - 1])